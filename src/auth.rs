@@ -0,0 +1,101 @@
+use crate::{
+    endpoints::Endpoints,
+    oauth::{
+        AuthorizationFlow, ClientCredentials, Scope,
+        client::{self, OAuthClient},
+    },
+    store::Store,
+};
+use chrono::{DateTime, Utc};
+use clap::Subcommand;
+
+#[derive(Clone, Copy, Subcommand)]
+pub enum AuthCommand {
+    /// Run the authorization flow and store the tokens, replacing any
+    /// existing ones
+    Login {
+        /// Don't open a browser or listen for the redirect: print the
+        /// authorization URL and read the redirect URL from stdin instead
+        #[arg(long)]
+        headless: bool,
+        /// Port for the loopback server receiving the authorization
+        /// redirect, any free port by default
+        #[arg(long)]
+        port: Option<u16>,
+        /// Access to request. Commands that need more ask for it when run
+        #[arg(long, value_enum, default_value_t = Scope::Readonly)]
+        scope: Scope,
+    },
+    /// Show the stored tokens' scopes and expiry
+    Status,
+    /// Revoke the stored tokens with Google and remove them from the store
+    Revoke,
+}
+
+pub async fn login(
+    store: &Store,
+    creds: ClientCredentials,
+    endpoints: Endpoints,
+    headless: bool,
+    port: Option<u16>,
+    scope: Scope,
+) -> eyre::Result<()> {
+    let flow = if headless {
+        AuthorizationFlow::Headless { port }
+    } else {
+        AuthorizationFlow::Browser { port }
+    };
+    let oauth_client = OAuthClient::authorize(flow, creds, endpoints, scope).await?;
+    store.set_tokens(oauth_client.tokens())?;
+    tracing::info!("authorization flow successful, tokens stored");
+    Ok(())
+}
+
+pub fn status(store: &Store) -> eyre::Result<()> {
+    let Some(tokens) = store.load_tokens()? else {
+        println!("not authorized, run `auth login`");
+        return Ok(());
+    };
+    match Scope::granted(tokens.scope.as_deref()) {
+        Some(level) => println!("access level: {level:?}"),
+        None => println!("access level: none of the Gmail scopes"),
+    }
+    match &tokens.scope {
+        Some(scope) => {
+            println!("scopes:");
+            for scope in scope.split_whitespace() {
+                println!("  {scope}");
+            }
+        }
+        None => println!("scopes: unknown (authorized before scopes were recorded)"),
+    }
+    println!("access token:  {}", expiry(tokens.expires_at));
+    match tokens.refresh_token_expires_at {
+        Some(expires_at) => println!("refresh token: {}", expiry(expires_at)),
+        None => println!("refresh token: does not expire"),
+    }
+    Ok(())
+}
+
+pub async fn revoke(store: &Store, endpoints: &Endpoints) -> eyre::Result<()> {
+    let Some(tokens) = store.load_tokens()? else {
+        println!("no stored tokens to revoke");
+        return Ok(());
+    };
+    let revoked = client::revoke(endpoints, &tokens.refresh_token).await;
+    // the local copy is useless either way, and keeping it around after a
+    // revoke was requested would be surprising
+    store.clear_tokens()?;
+    revoked?;
+    tracing::info!("tokens revoked and removed from the store");
+    Ok(())
+}
+
+fn expiry(at: DateTime<Utc>) -> String {
+    let at_str = at.format("%Y-%m-%d %H:%M:%S UTC");
+    if at > Utc::now() {
+        format!("expires {at_str}")
+    } else {
+        format!("expired {at_str} (refreshed automatically when needed)")
+    }
+}
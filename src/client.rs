@@ -1,19 +1,23 @@
 use crate::{
-    http::GenericClient,
+    http::{GenericClient, RateLimiter, Unauthorized, cassette::Cassette, error::DefaultError},
     model::{
         Attachment, AttachmentId, FullMessage, Label, LabelId, LabelList, MessageId,
         MinimalMessage, PageToken, RawMessage, UserProfile,
     },
     oauth::{AccessToken, TokenManager},
 };
-use reqwest::Url;
-use serde::{Deserialize, de::DeserializeOwned};
-use std::sync::{Arc, LazyLock};
+use reqwest::{Method, Url};
+use serde::{
+    Deserialize,
+    de::{DeserializeOwned, IgnoredAny},
+};
+use serde_json::json;
+use std::sync::Arc;
 use tokio::sync::{Mutex, mpsc};
 use tokio_stream::{Stream, wrappers::ReceiverStream};
 
-static BASE_URL: LazyLock<Url> =
-    LazyLock::new(|| Url::parse("https://gmail.googleapis.com/gmail/v1").expect("valid url"));
+/// Most ids `batch_delete_messages` accepts at once.
+pub const BATCH_DELETE_LIMIT: usize = 1000;
 
 #[derive(Clone)]
 pub struct GmailClient {
@@ -21,15 +25,26 @@ pub struct GmailClient {
 }
 
 struct GmailClientInner {
-    http_client: GenericClient,
+    http_client: GenericClient<DefaultError>,
     token_manager: Mutex<TokenManager>,
 }
 
 impl GmailClient {
-    pub fn new(token_manager: TokenManager) -> Self {
+    /// `base_url` is the Gmail API root, e.g. `Endpoints::gmail`. Requests
+    /// are recorded to, or replayed from, `cassette` if one is given.
+    pub fn new(
+        token_manager: TokenManager,
+        base_url: Url,
+        cassette: Option<Arc<Cassette>>,
+        rate_limiter: Option<Arc<RateLimiter>>,
+    ) -> Self {
         Self {
             inner: Arc::new(GmailClientInner {
-                http_client: token_manager.http_client().with_base_url(BASE_URL.clone()),
+                http_client: token_manager
+                    .http_client()
+                    .with_base_url(base_url)
+                    .with_cassette(cassette)
+                    .with_rate_limiter(rate_limiter),
                 token_manager: Mutex::new(token_manager),
             }),
         }
@@ -41,44 +56,77 @@ impl GmailClient {
         Ok(guard.access_token().clone())
     }
 
+    /// Sends `request` with a valid access token. If the token is rejected
+    /// anyway, it's refreshed and the request retried once.
+    async fn authorized<T, F>(&self, request: impl Fn(AccessToken) -> F) -> eyre::Result<T>
+    where
+        F: Future<Output = eyre::Result<T>>,
+    {
+        let access_token = self.access_token().await?;
+        match request(access_token.clone()).await {
+            Err(err) if err.downcast_ref::<Unauthorized>().is_some() => {
+                tracing::info!("access token rejected, refreshing it and retrying");
+                let access_token = {
+                    let mut guard = self.inner.token_manager.lock().await;
+                    guard.refresh_rejected(&access_token).await?;
+                    guard.access_token().clone()
+                };
+                request(access_token).await
+            }
+            result => result,
+        }
+    }
+
     pub async fn profile(&self) -> eyre::Result<UserProfile> {
-        self.inner
-            .http_client
-            .request(["users", "me", "profile"])
-            .access_token(self.access_token().await?)
-            .send()
-            .await
+        self.authorized(move |access_token| async move {
+            self.inner
+                .http_client
+                .request(["users", "me", "profile"])
+                .access_token(access_token)
+                .send()
+                .await
+        })
+        .await
     }
 
     pub async fn label(&self, id: &LabelId) -> eyre::Result<Label> {
-        self.inner
-            .http_client
-            .request(["users", "me", "labels", id.as_str()])
-            .access_token(self.access_token().await?)
-            .send()
-            .await
+        self.authorized(move |access_token| async move {
+            self.inner
+                .http_client
+                .request(["users", "me", "labels", id.as_str()])
+                .access_token(access_token)
+                .send()
+                .await
+        })
+        .await
     }
 
     pub async fn list_labels(&self) -> eyre::Result<LabelList> {
-        self.inner
-            .http_client
-            .request(["users", "me", "labels"])
-            .access_token(self.access_token().await?)
-            .send()
-            .await
+        self.authorized(move |access_token| async move {
+            self.inner
+                .http_client
+                .request(["users", "me", "labels"])
+                .access_token(access_token)
+                .send()
+                .await
+        })
+        .await
     }
 
     async fn message<M>(&self, id: &MessageId, format: &str) -> eyre::Result<M>
     where
         M: DeserializeOwned,
     {
-        self.inner
-            .http_client
-            .request(["users", "me", "messages", id.as_str()])
-            .access_token(self.access_token().await?)
-            .query(&[("format", format)])
-            .send()
-            .await
+        self.authorized(move |access_token| async move {
+            self.inner
+                .http_client
+                .request(["users", "me", "messages", id.as_str()])
+                .access_token(access_token)
+                .query(&[("format", format)])
+                .send()
+                .await
+        })
+        .await
     }
 
     pub async fn full_message(&self, id: &MessageId) -> eyre::Result<FullMessage> {
@@ -89,10 +137,51 @@ impl GmailClient {
         self.message(id, "raw").await
     }
 
-    pub fn list_messages(&self) -> impl Stream<Item = eyre::Result<MinimalMessage>> {
+    /// Moves a message to the trash, where Gmail deletes it after 30 days.
+    pub async fn trash_message(&self, id: &MessageId) -> eyre::Result<()> {
+        let _: IgnoredAny = self
+            .authorized(move |access_token| async move {
+                self.inner
+                    .http_client
+                    .request(["users", "me", "messages", id.as_str(), "trash"])
+                    .method(Method::POST)
+                    .access_token(access_token)
+                    .send()
+                    .await
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Permanently deletes up to `BATCH_DELETE_LIMIT` messages, skipping
+    /// trash. Ids that don't exist are ignored.
+    pub async fn batch_delete_messages(&self, ids: &[MessageId]) -> eyre::Result<()> {
+        assert!(ids.len() <= BATCH_DELETE_LIMIT);
+        let body = json!({ "ids": ids.iter().map(MessageId::as_str).collect::<Vec<_>>() });
+        self.authorized(|access_token| async {
+            self.inner
+                .http_client
+                .request(["users", "me", "messages", "batchDelete"])
+                .method(Method::POST)
+                .json(&body)
+                .access_token(access_token)
+                .send()
+                .await
+        })
+        .await
+    }
+
+    /// Lists the mailbox, or only the messages matching `filter`, a Gmail
+    /// search query.
+    pub fn list_messages(
+        &self,
+        filter: Option<String>,
+    ) -> impl Stream<Item = eyre::Result<MinimalMessage>> {
         #[derive(Debug, Deserialize)]
         #[serde(rename_all = "camelCase")]
         pub struct MessagesPage {
+            // absent when nothing matches
+            #[serde(default)]
             messages: Vec<MinimalMessage>,
             next_page_token: Option<PageToken>,
         }
@@ -100,19 +189,23 @@ impl GmailClient {
         let (tx, rx) = mpsc::channel(32);
         tokio::spawn(self.clone().result_wrapper(tx, |this, tx| async move {
             let fetch_page = async |page_token: Option<PageToken>| -> eyre::Result<MessagesPage> {
-                this.inner
-                    .http_client
-                    .request(["users", "me", "messages"])
-                    .access_token(this.access_token().await?)
-                    .maybe_query(
-                        page_token
-                            .as_ref()
-                            .map(|t| [("pageToken", t.as_str())])
-                            .as_ref()
-                            .map(|t| t.as_slice()),
-                    )
-                    .send()
-                    .await
+                let query: Vec<_> = page_token
+                    .as_ref()
+                    .map(|t| ("pageToken", t.as_str()))
+                    .into_iter()
+                    .chain(filter.as_deref().map(|q| ("q", q)))
+                    .collect();
+                let (this, query) = (&this, query.as_slice());
+                this.authorized(move |access_token| async move {
+                    this.inner
+                        .http_client
+                        .request(["users", "me", "messages"])
+                        .access_token(access_token)
+                        .query(query)
+                        .send()
+                        .await
+                })
+                .await
             };
 
             let mut page = fetch_page(None).await?;
@@ -139,19 +232,22 @@ impl GmailClient {
         message_id: &MessageId,
         attachment_id: &AttachmentId,
     ) -> eyre::Result<Attachment> {
-        self.inner
-            .http_client
-            .request([
-                "users",
-                "me",
-                "messages",
-                message_id.as_str(),
-                "attachments",
-                attachment_id.as_str(),
-            ])
-            .access_token(self.access_token().await?)
-            .send()
-            .await
+        self.authorized(move |access_token| async move {
+            self.inner
+                .http_client
+                .request([
+                    "users",
+                    "me",
+                    "messages",
+                    message_id.as_str(),
+                    "attachments",
+                    attachment_id.as_str(),
+                ])
+                .access_token(access_token)
+                .send()
+                .await
+        })
+        .await
     }
 
     async fn result_wrapper<T, F>(
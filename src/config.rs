@@ -0,0 +1,171 @@
+//! The optional TOML configuration file. Command-line flags, and the
+//! environment variables standing in for them, take precedence over it.
+//!
+//! ```toml
+//! db = "archive.db"
+//! secrets_file = "client_secret.json"
+//!
+//! [sync]
+//! include_labels = ["INBOX", "Receipts"]
+//! exclude_labels = ["CATEGORY_PROMOTIONS"]
+//! query = "newer_than:10y"
+//! concurrency = 4
+//! requests_per_second = 20
+//!
+//! # selected with `--account work`, falling back to the settings above
+//! [accounts.work]
+//! db = "work.db"
+//! service_account = "key.json"
+//! impersonate = "me@example.com"
+//!
+//! # used with `export --target yearly`
+//! [export.yearly]
+//! format = "mbox"
+//! output = "archive.mbox"
+//! query = "older_than:1y"
+//! ```
+
+use crate::export::ExportFormat;
+use eyre::{WrapErr, eyre};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    // the same as `AccountConfig`, which can't be flattened in here since
+    // unknown fields are rejected
+    db: Option<PathBuf>,
+    secrets_file: Option<PathBuf>,
+    service_account: Option<PathBuf>,
+    impersonate: Option<String>,
+    #[serde(default)]
+    sync: SyncConfig,
+    api_url: Option<String>,
+    #[serde(default)]
+    accounts: BTreeMap<String, AccountConfig>,
+    #[serde(default)]
+    export: BTreeMap<String, ExportTarget>,
+}
+
+/// The settings of an account. All of them are optional, also on the
+/// command line.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub db: Option<PathBuf>,
+    pub secrets_file: Option<PathBuf>,
+    pub service_account: Option<PathBuf>,
+    pub impersonate: Option<String>,
+    #[serde(default)]
+    pub sync: SyncConfig,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Label names or ids: only messages with any of them are synced.
+    pub include_labels: Option<Vec<String>>,
+    pub exclude_labels: Option<Vec<String>>,
+    /// A Gmail search query messages must match to be synced.
+    pub query: Option<String>,
+    /// Messages fetched at the same time.
+    pub concurrency: Option<usize>,
+    pub requests_per_second: Option<f64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExportTarget {
+    pub format: Option<ExportFormat>,
+    pub output: PathBuf,
+    pub query: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("failed to read config {}", path.display()))?;
+        toml::from_str(&contents).wrap_err_with(|| format!("invalid config {}", path.display()))
+    }
+
+    pub fn api_url(&self) -> eyre::Result<Option<Url>> {
+        self.api_url
+            .as_deref()
+            .map(|url| Url::parse(url).wrap_err_with(|| format!("invalid api_url {url:?}")))
+            .transpose()
+    }
+
+    /// The settings of account `name`, completed by the top-level ones, or
+    /// the top-level ones alone.
+    pub fn account(&self, name: Option<&str>) -> eyre::Result<AccountConfig> {
+        let defaults = AccountConfig {
+            db: self.db.clone(),
+            secrets_file: self.secrets_file.clone(),
+            service_account: self.service_account.clone(),
+            impersonate: self.impersonate.clone(),
+            sync: self.sync.clone(),
+        };
+        let Some(name) = name else {
+            return Ok(defaults);
+        };
+        let account = self.accounts.get(name).ok_or_else(|| {
+            eyre!(
+                "no account {name:?} in the config, known accounts: {}",
+                self.accounts.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        Ok(account.clone().or(defaults))
+    }
+
+    pub fn export_target(&self, name: &str) -> eyre::Result<&ExportTarget> {
+        self.export
+            .get(name)
+            .ok_or_else(|| eyre!("no export target {name:?} in the config"))
+    }
+}
+
+impl AccountConfig {
+    fn or(self, fallback: AccountConfig) -> AccountConfig {
+        AccountConfig {
+            db: self.db.or(fallback.db),
+            secrets_file: self.secrets_file.or(fallback.secrets_file),
+            service_account: self.service_account.or(fallback.service_account),
+            impersonate: self.impersonate.or(fallback.impersonate),
+            sync: SyncConfig {
+                include_labels: self.sync.include_labels.or(fallback.sync.include_labels),
+                exclude_labels: self.sync.exclude_labels.or(fallback.sync.exclude_labels),
+                query: self.sync.query.or(fallback.sync.query),
+                concurrency: self.sync.concurrency.or(fallback.sync.concurrency),
+                requests_per_second: self
+                    .sync
+                    .requests_per_second
+                    .or(fallback.sync.requests_per_second),
+            },
+        }
+    }
+}
+
+impl SyncConfig {
+    /// The Gmail search query selecting the messages to sync, if they're
+    /// restricted.
+    pub fn filter(&self) -> Option<String> {
+        let label = |name: &String| format!("label:{}", name.replace(' ', "-"));
+        let mut terms = Vec::new();
+        if let Some(include) = self.include_labels.as_ref().filter(|l| !l.is_empty()) {
+            terms.push(format!(
+                "{{{}}}",
+                include.iter().map(label).collect::<Vec<_>>().join(" ")
+            ));
+        }
+        for name in self.exclude_labels.iter().flatten() {
+            terms.push(format!("-{}", label(name)));
+        }
+        terms.extend(self.query.clone());
+        (!terms.is_empty()).then(|| terms.join(" "))
+    }
+}
@@ -0,0 +1,126 @@
+use crate::store::{Correspondent, Store};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// vCard lines longer than this many octets must be folded.
+const VCARD_LINE_LIMIT: usize = 75;
+
+pub fn contacts(store: &Store, vcard: Option<&Path>, limit: usize) -> eyre::Result<()> {
+    let count = store.rebuild_correspondents()?;
+    tracing::info!("found {count} correspondents");
+    match vcard {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            for correspondent in store.correspondents(None)? {
+                write_vcard(&mut writer, &correspondent)?;
+            }
+            writer.flush()?;
+            tracing::info!("address book written to {}", path.display());
+        }
+        None => {
+            for c in store.correspondents(Some(limit))? {
+                println!(
+                    "{:>6} sent {:>6} received  {} - {}  {}",
+                    c.sent_count,
+                    c.received_count,
+                    c.first_seen.format("%Y-%m-%d"),
+                    c.last_seen.format("%Y-%m-%d"),
+                    match &c.display_name {
+                        Some(name) => format!("{name} <{}>", c.address),
+                        None => c.address.clone(),
+                    },
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a vCard 3.0 entry, which is what most address books import
+/// without complaint.
+fn write_vcard(writer: &mut impl Write, correspondent: &Correspondent) -> eyre::Result<()> {
+    let name = correspondent
+        .display_name
+        .as_deref()
+        .unwrap_or(&correspondent.address);
+    let lines = [
+        "BEGIN:VCARD".to_owned(),
+        "VERSION:3.0".to_owned(),
+        format!("FN:{}", escape(name)),
+        format!(
+            "N:{}",
+            structured_name(correspondent.display_name.as_deref())
+        ),
+        format!("EMAIL;TYPE=INTERNET:{}", escape(&correspondent.address)),
+        format!(
+            "NOTE:{}",
+            escape(&format!(
+                "{} messages sent, {} received, between {} and {}",
+                correspondent.sent_count,
+                correspondent.received_count,
+                correspondent.first_seen.format("%Y-%m-%d"),
+                correspondent.last_seen.format("%Y-%m-%d"),
+            ))
+        ),
+        "END:VCARD".to_owned(),
+    ];
+    for line in lines {
+        write_folded(writer, &line)?;
+    }
+    Ok(())
+}
+
+/// The `N` property vCard 3.0 requires, as family and given names guessed
+/// from the display name: "Last, First" or else the last word as the family
+/// name. Left empty for correspondents known only by address.
+fn structured_name(display_name: Option<&str>) -> String {
+    let (family, given) = match display_name.map(str::trim) {
+        None | Some("") => ("", ""),
+        Some(name) => match name.split_once(',') {
+            Some((family, given)) => (family.trim(), given.trim()),
+            None => match name.rsplit_once(char::is_whitespace) {
+                Some((given, family)) => (family, given.trim()),
+                None => (name, ""),
+            },
+        },
+    };
+    format!("{};{};;;", escape(family), escape(given))
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' | ',' | ';' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            '\r' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_folded(writer: &mut impl Write, line: &str) -> eyre::Result<()> {
+    let mut rest = line;
+    let mut limit = VCARD_LINE_LIMIT;
+    loop {
+        if rest.len() <= limit {
+            write!(writer, "{rest}\r\n")?;
+            return Ok(());
+        }
+        let mut split = limit;
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        write!(writer, "{}\r\n ", &rest[..split])?;
+        rest = &rest[split..];
+        // continuation lines start with a space, which counts towards the limit
+        limit = VCARD_LINE_LIMIT - 1;
+    }
+}
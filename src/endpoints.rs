@@ -0,0 +1,44 @@
+//! Where the Google APIs are reached. Google's by default, but they can all
+//! be moved under another base URL, such as the one of `fake-server`.
+
+use reqwest::Url;
+
+#[derive(Debug, Clone)]
+pub struct Endpoints {
+    pub gmail: Url,
+    pub token: Url,
+    pub authorization: Url,
+    pub revoke: Url,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        let parse = |url| Url::parse(url).expect("valid url");
+        Self {
+            gmail: parse("https://gmail.googleapis.com/gmail/v1"),
+            token: parse("https://oauth2.googleapis.com/token"),
+            authorization: parse("https://accounts.google.com/o/oauth2/v2/auth"),
+            revoke: parse("https://oauth2.googleapis.com/revoke"),
+        }
+    }
+}
+
+impl Endpoints {
+    /// All endpoints under `base`, with the same paths as Google's.
+    pub fn under(base: &Url) -> Self {
+        let join = |segments: &[&str]| {
+            let mut url = base.clone();
+            url.path_segments_mut()
+                .expect("base url")
+                .pop_if_empty()
+                .extend(segments);
+            url
+        };
+        Self {
+            gmail: join(&["gmail", "v1"]),
+            token: join(&["token"]),
+            authorization: join(&["o", "oauth2", "v2", "auth"]),
+            revoke: join(&["revoke"]),
+        }
+    }
+}
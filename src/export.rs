@@ -0,0 +1,99 @@
+use crate::{model::MessageId, query, store::Store};
+use clap::ValueEnum;
+use eyre::eyre;
+use serde::Deserialize;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// A single mbox file (mboxrd flavour), as produced by Google Takeout
+    Mbox,
+    /// A directory with one `<id>.eml` file per message
+    Eml,
+}
+
+/// Exports the raw messages matching `query` (everything if empty). Messages
+/// that aren't completely stored yet are skipped.
+pub fn export(
+    store: &Store,
+    format: ExportFormat,
+    output: &Path,
+    query: &[String],
+) -> eyre::Result<()> {
+    let query = query::compile(&query::parse(&query.join(" "))?);
+    let ids = store.complete_message_ids(&query)?;
+    let mut exported = 0;
+    let mut sink = match format {
+        ExportFormat::Mbox => Sink::Mbox(BufWriter::new(File::create(output)?)),
+        ExportFormat::Eml => {
+            fs::create_dir_all(output)?;
+            Sink::Eml(output)
+        }
+    };
+    for id in &ids {
+        let raw = store
+            .raw_message(id)?
+            .ok_or_else(|| eyre!("raw message {id} missing from a complete message"))?;
+        let date = store
+            .message_summary(id)?
+            .map(|summary| summary.internal_date)
+            .unwrap_or_default();
+        match &mut sink {
+            Sink::Mbox(writer) => {
+                write_mbox_entry(writer, id, &date.format("%a %b %e %T %Y").to_string(), &raw)?
+            }
+            Sink::Eml(dir) => fs::write(dir.join(format!("{id}.eml")), &raw)?,
+        }
+        exported += 1;
+    }
+    if let Sink::Mbox(writer) = &mut sink {
+        writer.flush()?;
+    }
+    tracing::info!("exported {exported} messages to {}", output.display());
+    let partial = store.archive_status()?.partial_messages;
+    if partial > 0 {
+        tracing::warn!(
+            "the archive has {partial} partially stored messages, which aren't exported: \
+            run `sync` to complete them"
+        );
+    }
+    Ok(())
+}
+
+enum Sink<'a> {
+    Mbox(BufWriter<File>),
+    Eml(&'a Path),
+}
+
+/// Writes one mboxrd entry: any line that looks like a `From ` separator,
+/// with any number of leading `>`, gets one more `>`.
+fn write_mbox_entry(
+    writer: &mut impl Write,
+    id: &MessageId,
+    date: &str,
+    raw: &[u8],
+) -> eyre::Result<()> {
+    // Takeout puts the decimal form of the message id in the separator, which
+    // is also what `import` expects
+    match u64::from_str_radix(id.as_str(), 16) {
+        Ok(decimal) => writeln!(writer, "From {decimal}@xxx {date}")?,
+        Err(_) => writeln!(writer, "From {id}@gmail-archiver {date}")?,
+    }
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            writer.write_all(b">")?;
+        }
+        writer.write_all(line)?;
+    }
+    if !raw.ends_with(b"\n") {
+        writer.write_all(b"\n")?;
+    }
+    writer.write_all(b"\n")?;
+    Ok(())
+}
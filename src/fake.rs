@@ -0,0 +1,894 @@
+//! A fake Gmail API and OAuth server over a generated mailbox, for
+//! integration tests and offline demos. The archiver talks to it when
+//! started with `--api-url`.
+//!
+//! Consent is granted right away and any client credentials are accepted.
+//! Every `fail_every`th Gmail request fails with a rate limit or server
+//! error, to exercise retries.
+
+use axum::{
+    Form, Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{HeaderValue, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+};
+use base64::prelude::*;
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::net::TcpListener;
+
+const ACCESS_TOKEN_PREFIX: &str = "fake-access-";
+const REFRESH_TOKEN_PREFIX: &str = "fake-refresh-";
+const CODE_PREFIX: &str = "fake-code-";
+const TOKEN_LIFETIME_SECS: u64 = 3599;
+const MAX_PAGE_SIZE: usize = 500;
+const EMAIL_ADDRESS: &str = "me@example.com";
+
+const SENDERS: &[&str] = &[
+    "Alice Example <alice@example.com>",
+    "Bob <bob@example.org>",
+    "Shop Newsletter <news@shop.example>",
+    "Carol <carol@example.net>",
+    "Travel Agency <bookings@travel.example>",
+];
+
+const SYSTEM_LABELS: &[&str] = &[
+    "INBOX",
+    "SENT",
+    "UNREAD",
+    "STARRED",
+    "IMPORTANT",
+    "CATEGORY_PERSONAL",
+    "CATEGORY_PROMOTIONS",
+    "CATEGORY_UPDATES",
+];
+/// `(id, name)` pairs.
+const USER_LABELS: &[(&str, &str)] = &[("Label_1", "Receipts"), ("Label_2", "Travel")];
+
+#[derive(Debug, Clone, Copy)]
+pub struct FakeConfig {
+    pub messages: usize,
+    pub page_size: usize,
+    pub fail_every: Option<u64>,
+}
+
+pub async fn serve(bind: SocketAddr, config: FakeConfig) -> eyre::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    let addr = listener.local_addr()?;
+    tracing::info!(
+        messages = config.messages,
+        "fake Google server on http://{addr}, use it with `--api-url http://{addr}`"
+    );
+    axum::serve(listener, router(config)).await?;
+    Ok(())
+}
+
+pub fn router(config: FakeConfig) -> Router {
+    let state = FakeState::new(config);
+    let gmail = Router::new()
+        .route("/users/me/profile", get(profile))
+        .route("/users/me/labels", get(labels))
+        .route("/users/me/labels/{id}", get(label))
+        .route("/users/me/messages", get(list_messages))
+        .route("/users/me/messages/{id}", get(message))
+        .route("/users/me/messages/{id}/trash", post(trash_message))
+        .route("/users/me/messages/batchDelete", post(batch_delete))
+        .route(
+            "/users/me/messages/{id}/attachments/{attachment_id}",
+            get(attachment),
+        )
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            inject_failures,
+        ))
+        .layer(middleware::from_fn(check_access_token));
+    Router::new()
+        .nest("/gmail/v1", gmail)
+        .route("/o/oauth2/v2/auth", get(authorize))
+        .route("/token", post(token))
+        .route("/revoke", post(revoke))
+        .with_state(state)
+}
+
+#[derive(Clone)]
+struct FakeState {
+    inner: Arc<FakeStateInner>,
+}
+
+struct FakeStateInner {
+    config: FakeConfig,
+    messages: Vec<FakeMessage>,
+    requests: AtomicU64,
+    issued_tokens: AtomicU64,
+    revoked: Mutex<HashSet<String>>,
+}
+
+impl FakeState {
+    fn new(config: FakeConfig) -> Self {
+        Self {
+            inner: Arc::new(FakeStateInner {
+                config,
+                messages: (0..config.messages)
+                    .map(|i| FakeMessage::generate(i, config.messages))
+                    .collect(),
+                requests: AtomicU64::new(0),
+                issued_tokens: AtomicU64::new(0),
+                revoked: Mutex::new(HashSet::new()),
+            }),
+        }
+    }
+
+    fn message(&self, id: &str) -> Option<&FakeMessage> {
+        self.inner.messages.iter().find(|m| m.id == id)
+    }
+
+    fn issue_token(&self, prefix: &str) -> String {
+        let n = self.inner.issued_tokens.fetch_add(1, Ordering::Relaxed);
+        format!("{prefix}{n}")
+    }
+}
+
+struct FakeMessage {
+    id: String,
+    thread_id: String,
+    label_ids: Vec<&'static str>,
+    internal_date: DateTime<Utc>,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+    attachment: Option<FakeAttachment>,
+    raw: Vec<u8>,
+}
+
+struct FakeAttachment {
+    id: String,
+    filename: String,
+    data: Vec<u8>,
+}
+
+impl FakeMessage {
+    /// Message `i` of `total`, newest first like Gmail lists them. Threads
+    /// group three consecutive messages, and every fifth one has an
+    /// attachment.
+    fn generate(i: usize, total: usize) -> Self {
+        let id_of = |i: usize| format!("{:016x}", 0x1890_0000_0000_0000_u64 + (total - i) as u64);
+        let id = id_of(i);
+        let newest = Utc.with_ymd_and_hms(2025, 6, 1, 12, 0, 0).unwrap();
+        let internal_date = newest - TimeDelta::hours(9 * i as i64);
+
+        let mut label_ids = vec![if i.is_multiple_of(7) { "SENT" } else { "INBOX" }];
+        label_ids.push(match i % 4 {
+            0 => "CATEGORY_PROMOTIONS",
+            1 => "CATEGORY_UPDATES",
+            _ => "CATEGORY_PERSONAL",
+        });
+        if i.is_multiple_of(3) {
+            label_ids.push("UNREAD");
+        }
+        if i.is_multiple_of(13) {
+            label_ids.push("STARRED");
+        }
+        if i.is_multiple_of(10) {
+            label_ids.push("Label_1");
+        }
+        if i.is_multiple_of(11) {
+            label_ids.push("Label_2");
+        }
+
+        let (from, to) = if i.is_multiple_of(7) {
+            (
+                EMAIL_ADDRESS.to_owned(),
+                SENDERS[i % SENDERS.len()].to_owned(),
+            )
+        } else {
+            (
+                SENDERS[i % SENDERS.len()].to_owned(),
+                EMAIL_ADDRESS.to_owned(),
+            )
+        };
+        let subject = format!("Message {} in thread {}", i % 3 + 1, i / 3 + 1);
+        let body = format!(
+            "Hello,\r\n\r\nthis is fake message number {i}, sent on {}.\r\n\r\nRegards\r\n",
+            internal_date.format("%Y-%m-%d")
+        );
+        let attachment = i.is_multiple_of(5).then(|| FakeAttachment {
+            id: format!("att-{id}"),
+            filename: format!("report-{i}.csv"),
+            data: (0..=i % 20)
+                .map(|row| format!("{row},{}\n", row * i))
+                .collect::<String>()
+                .into_bytes(),
+        });
+        let headers = vec![
+            ("From", from),
+            ("To", to),
+            ("Subject", subject),
+            ("Date", internal_date.to_rfc2822()),
+            ("Message-ID", format!("<{id}@fake.example>")),
+            ("MIME-Version", "1.0".to_owned()),
+        ];
+
+        let mut message = Self {
+            thread_id: id_of(i - i % 3),
+            id,
+            label_ids,
+            internal_date,
+            headers,
+            body,
+            attachment,
+            raw: Vec::new(),
+        };
+        message.raw = message.render_raw();
+        message
+    }
+
+    fn boundary(&self) -> String {
+        format!("fake-{}", self.id)
+    }
+
+    fn render_raw(&self) -> Vec<u8> {
+        let mut raw = String::new();
+        for (name, value) in &self.headers {
+            raw += &format!("{name}: {value}\r\n");
+        }
+        match &self.attachment {
+            None => {
+                raw += "Content-Type: text/plain; charset=UTF-8\r\n\r\n";
+                raw += &self.body;
+            }
+            Some(attachment) => {
+                let boundary = self.boundary();
+                raw += &format!("Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n");
+                raw += &format!("--{boundary}\r\nContent-Type: text/plain; charset=UTF-8\r\n\r\n");
+                raw += &self.body;
+                raw += &format!(
+                    "\r\n--{boundary}\r\nContent-Type: text/csv; name=\"{0}\"\r\n\
+                    Content-Disposition: attachment; filename=\"{0}\"\r\n\
+                    Content-Transfer-Encoding: base64\r\n\r\n{1}\r\n--{boundary}--\r\n",
+                    attachment.filename,
+                    BASE64_STANDARD.encode(&attachment.data),
+                );
+            }
+        }
+        raw.into_bytes()
+    }
+
+    fn snippet(&self) -> String {
+        self.body.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// The fields common to all formats.
+    fn metadata(&self) -> serde_json::Map<String, Value> {
+        let Value::Object(map) = json!({
+            "id": self.id,
+            "threadId": self.thread_id,
+            "labelIds": self.label_ids,
+            "snippet": self.snippet(),
+            "historyId": "1000",
+            "internalDate": self.internal_date.timestamp_millis().to_string(),
+            "sizeEstimate": self.raw.len(),
+        }) else {
+            unreachable!()
+        };
+        map
+    }
+
+    fn payload(&self) -> Value {
+        let headers = |headers: &[(&str, String)]| -> Vec<Value> {
+            headers
+                .iter()
+                .map(|(name, value)| json!({ "name": name, "value": value }))
+                .collect()
+        };
+        let text_part = |part_id: &str, headers: Vec<Value>| {
+            json!({
+                "partId": part_id,
+                "mimeType": "text/plain",
+                "filename": "",
+                "headers": headers,
+                "body": {
+                    "size": self.body.len(),
+                    "data": BASE64_URL_SAFE.encode(&self.body),
+                },
+            })
+        };
+        let text_type = ("Content-Type", "text/plain; charset=UTF-8".to_owned());
+        let Some(attachment) = &self.attachment else {
+            let mut all = self.headers.clone();
+            all.push(text_type);
+            return text_part("", headers(&all));
+        };
+        let mut all = self.headers.clone();
+        all.push((
+            "Content-Type",
+            format!("multipart/mixed; boundary=\"{}\"", self.boundary()),
+        ));
+        json!({
+            "partId": "",
+            "mimeType": "multipart/mixed",
+            "filename": "",
+            "headers": headers(&all),
+            "body": { "size": 0 },
+            "parts": [
+                text_part("0", headers(&[text_type])),
+                {
+                    "partId": "1",
+                    "mimeType": "text/csv",
+                    "filename": attachment.filename,
+                    "headers": headers(&[
+                        ("Content-Type", format!("text/csv; name=\"{}\"", attachment.filename)),
+                        (
+                            "Content-Disposition",
+                            format!("attachment; filename=\"{}\"", attachment.filename),
+                        ),
+                        ("Content-Transfer-Encoding", "base64".to_owned()),
+                    ]),
+                    "body": {
+                        "size": attachment.data.len(),
+                        "attachmentId": attachment.id,
+                    },
+                },
+            ],
+        })
+    }
+}
+
+/// Error bodies shaped like Google's, so that they're classified the same.
+fn google_error(status: StatusCode, reason: &str, message: &str) -> Response {
+    let status_name = match status {
+        StatusCode::BAD_REQUEST => "INVALID_ARGUMENT",
+        StatusCode::UNAUTHORIZED => "UNAUTHENTICATED",
+        StatusCode::FORBIDDEN => "PERMISSION_DENIED",
+        StatusCode::NOT_FOUND => "NOT_FOUND",
+        StatusCode::TOO_MANY_REQUESTS => "RESOURCE_EXHAUSTED",
+        StatusCode::SERVICE_UNAVAILABLE => "UNAVAILABLE",
+        _ => "INTERNAL",
+    };
+    let body = json!({
+        "error": {
+            "code": status.as_u16(),
+            "message": message,
+            "errors": [{ "message": message, "domain": "global", "reason": reason }],
+            "status": status_name,
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+fn not_found() -> Response {
+    google_error(
+        StatusCode::NOT_FOUND,
+        "notFound",
+        "Requested entity was not found.",
+    )
+}
+
+async fn check_access_token(request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token.starts_with(ACCESS_TOKEN_PREFIX));
+    if !authorized {
+        return google_error(
+            StatusCode::UNAUTHORIZED,
+            "authError",
+            "Request had invalid authentication credentials.",
+        );
+    }
+    next.run(request).await
+}
+
+/// Cycles through the failures Gmail is known to answer with.
+async fn inject_failures(State(state): State<FakeState>, request: Request, next: Next) -> Response {
+    let n = state.inner.requests.fetch_add(1, Ordering::Relaxed) + 1;
+    let Some(every) = state
+        .inner
+        .config
+        .fail_every
+        .filter(|&every| n.is_multiple_of(every))
+    else {
+        return next.run(request).await;
+    };
+    let failure = match (n / every) % 4 {
+        0 => {
+            let mut response = google_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rateLimitExceeded",
+                "Too many requests.",
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("1"));
+            response
+        }
+        1 => google_error(
+            StatusCode::FORBIDDEN,
+            "userRateLimitExceeded",
+            "User-rate limit exceeded.",
+        ),
+        2 => google_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "backendError",
+            "The service is currently unavailable.",
+        ),
+        _ => google_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "backendError",
+            "Internal error encountered.",
+        ),
+    };
+    tracing::info!(uri = %request.uri(), status = %failure.status(), "injecting failure");
+    failure
+}
+
+async fn profile(State(state): State<FakeState>) -> Json<Value> {
+    let messages = &state.inner.messages;
+    Json(json!({
+        "emailAddress": EMAIL_ADDRESS,
+        "messagesTotal": messages.len(),
+        "threadsTotal": messages.len().div_ceil(3),
+        "historyId": "1000",
+    }))
+}
+
+fn label_json(id: &str) -> Option<Value> {
+    if SYSTEM_LABELS.contains(&id) {
+        return Some(json!({ "id": id, "name": id, "type": "system" }));
+    }
+    let (id, name) = USER_LABELS.iter().find(|(label_id, _)| *label_id == id)?;
+    Some(json!({
+        "id": id,
+        "name": name,
+        "type": "user",
+        "messageListVisibility": "show",
+        "labelListVisibility": "labelShow",
+        "color": { "textColor": "#ffffff", "backgroundColor": "#4986e7" },
+    }))
+}
+
+async fn labels() -> Json<Value> {
+    let labels: Vec<Value> = SYSTEM_LABELS
+        .iter()
+        .copied()
+        .chain(USER_LABELS.iter().map(|(id, _)| *id))
+        .filter_map(label_json)
+        .collect();
+    Json(json!({ "labels": labels }))
+}
+
+async fn label(Path(id): Path<String>) -> Response {
+    match label_json(&id) {
+        Some(label) => Json(label).into_response(),
+        None => not_found(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListParams {
+    page_token: Option<String>,
+    max_results: Option<usize>,
+}
+
+/// Page tokens are the offset of the page's first message.
+async fn list_messages(
+    State(state): State<FakeState>,
+    Query(params): Query<ListParams>,
+) -> Response {
+    let messages = &state.inner.messages;
+    let start = match params.page_token.as_deref().map(str::parse::<usize>) {
+        None => 0,
+        Some(Ok(start)) if start <= messages.len() => start,
+        Some(_) => {
+            return google_error(
+                StatusCode::BAD_REQUEST,
+                "invalidArgument",
+                "Invalid pageToken",
+            );
+        }
+    };
+    let page_size = params
+        .max_results
+        .unwrap_or(state.inner.config.page_size)
+        .clamp(1, MAX_PAGE_SIZE);
+    let end = (start + page_size).min(messages.len());
+    let page: Vec<Value> = messages[start..end]
+        .iter()
+        .map(|m| json!({ "id": m.id, "threadId": m.thread_id }))
+        .collect();
+    let mut body = json!({ "messages": page, "resultSizeEstimate": messages.len() });
+    if end < messages.len() {
+        body["nextPageToken"] = json!(end.to_string());
+    }
+    Json(body).into_response()
+}
+
+#[derive(Deserialize)]
+struct MessageParams {
+    format: Option<String>,
+}
+
+async fn message(
+    State(state): State<FakeState>,
+    Path(id): Path<String>,
+    Query(params): Query<MessageParams>,
+) -> Response {
+    let Some(message) = state.message(&id) else {
+        return not_found();
+    };
+    let mut body = message.metadata();
+    match params.format.as_deref().unwrap_or("full") {
+        "full" => {
+            body.insert("payload".to_owned(), message.payload());
+        }
+        "raw" => {
+            body.insert(
+                "raw".to_owned(),
+                json!(BASE64_URL_SAFE.encode(&message.raw)),
+            );
+        }
+        "minimal" => (),
+        format => {
+            return google_error(
+                StatusCode::BAD_REQUEST,
+                "invalidArgument",
+                &format!("Invalid value for format: {format}"),
+            );
+        }
+    }
+    Json(Value::Object(body)).into_response()
+}
+
+/// Nothing is removed: the mailbox is the same on every run.
+async fn trash_message(State(state): State<FakeState>, Path(id): Path<String>) -> Response {
+    match state.message(&id) {
+        Some(message) => Json(Value::Object(message.metadata())).into_response(),
+        None => not_found(),
+    }
+}
+
+async fn batch_delete(Json(body): Json<Value>) -> Response {
+    match body.get("ids").and_then(Value::as_array) {
+        Some(ids) if ids.len() <= 1000 => StatusCode::NO_CONTENT.into_response(),
+        _ => google_error(StatusCode::BAD_REQUEST, "invalidArgument", "Invalid ids"),
+    }
+}
+
+async fn attachment(
+    State(state): State<FakeState>,
+    Path((id, attachment_id)): Path<(String, String)>,
+) -> Response {
+    let Some(message) = state.message(&id) else {
+        return not_found();
+    };
+    match &message.attachment {
+        Some(attachment) if attachment.id == attachment_id => Json(json!({
+            "size": attachment.data.len(),
+            "data": BASE64_URL_SAFE.encode(&attachment.data),
+        }))
+        .into_response(),
+        _ => google_error(
+            StatusCode::NOT_FOUND,
+            "notFound",
+            "Invalid attachment token",
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct AuthorizeParams {
+    redirect_uri: String,
+    state: String,
+    scope: String,
+}
+
+/// Skips the consent screen, redirecting right away with a code that
+/// remembers the requested scope.
+async fn authorize(Query(params): Query<AuthorizeParams>) -> Response {
+    let Ok(mut url) = Url::parse(&params.redirect_uri) else {
+        return (StatusCode::BAD_REQUEST, "invalid redirect_uri").into_response();
+    };
+    url.query_pairs_mut()
+        .append_pair("code", &format!("{CODE_PREFIX}{}", params.scope))
+        .append_pair("scope", &params.scope)
+        .append_pair("state", &params.state);
+    Redirect::to(url.as_str()).into_response()
+}
+
+#[derive(Deserialize)]
+struct TokenParams {
+    grant_type: String,
+    code: Option<String>,
+    refresh_token: Option<String>,
+}
+
+fn oauth_error(error: &str, description: &str) -> Response {
+    let body = json!({ "error": error, "error_description": description });
+    (StatusCode::BAD_REQUEST, Json(body)).into_response()
+}
+
+async fn token(State(state): State<FakeState>, Form(params): Form<TokenParams>) -> Response {
+    match params.grant_type.as_str() {
+        "authorization_code" => {
+            let Some(scope) = params
+                .code
+                .as_deref()
+                .and_then(|c| c.strip_prefix(CODE_PREFIX))
+            else {
+                return oauth_error("invalid_grant", "Malformed auth code.");
+            };
+            Json(json!({
+                "access_token": state.issue_token(ACCESS_TOKEN_PREFIX),
+                "expires_in": TOKEN_LIFETIME_SECS,
+                "refresh_token": state.issue_token(REFRESH_TOKEN_PREFIX),
+                "scope": scope,
+                "token_type": "Bearer",
+            }))
+            .into_response()
+        }
+        "refresh_token" => {
+            let refresh_token = params.refresh_token.unwrap_or_default();
+            let revoked = state.inner.revoked.lock().unwrap().contains(&refresh_token);
+            if revoked || !refresh_token.starts_with(REFRESH_TOKEN_PREFIX) {
+                return oauth_error("invalid_grant", "Token has been expired or revoked.");
+            }
+            Json(json!({
+                "access_token": state.issue_token(ACCESS_TOKEN_PREFIX),
+                "expires_in": TOKEN_LIFETIME_SECS,
+                "token_type": "Bearer",
+            }))
+            .into_response()
+        }
+        // the assertion isn't verified
+        "urn:ietf:params:oauth:grant-type:jwt-bearer" => Json(json!({
+            "access_token": state.issue_token(ACCESS_TOKEN_PREFIX),
+            "expires_in": TOKEN_LIFETIME_SECS,
+            "token_type": "Bearer",
+        }))
+        .into_response(),
+        grant_type => oauth_error(
+            "unsupported_grant_type",
+            &format!("Invalid grant_type: {grant_type}"),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeParams {
+    token: String,
+}
+
+async fn revoke(State(state): State<FakeState>, Form(params): Form<RevokeParams>) -> StatusCode {
+    state.inner.revoked.lock().unwrap().insert(params.token);
+    StatusCode::OK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::GmailClient,
+        config::SyncConfig,
+        endpoints::Endpoints,
+        http::{FailureKind, error::DefaultError, is_failure},
+        model::MessageId,
+        oauth::{
+            AuthzCode, ClientCredentials, CodeVerifier, InvalidGrant, OAuthTokens, Scope,
+            TokenManager,
+            client::{OAuthClient, PartialOAuthClient},
+            service_account::{ServiceAccountClient, ServiceAccountKey},
+        },
+        progress::ProgressFormat,
+        store::Store,
+        sync,
+    };
+    use tokio_stream::StreamExt;
+    use tokio_util::sync::CancellationToken;
+
+    /// Serves a fake mailbox on an ephemeral port.
+    async fn start(config: FakeConfig) -> Endpoints {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router(config)).await });
+        Endpoints::under(&Url::parse(&format!("http://{addr}")).unwrap())
+    }
+
+    fn mailbox(messages: usize) -> FakeConfig {
+        FakeConfig {
+            messages,
+            page_size: 5,
+            fail_every: None,
+        }
+    }
+
+    fn credentials() -> ClientCredentials {
+        ClientCredentials {
+            id: serde_json::from_value(json!("client-id")).unwrap(),
+            secret: serde_json::from_value(json!("client-secret")).unwrap(),
+        }
+    }
+
+    /// Goes through the code exchange, as after the consent screen.
+    async fn authorized_client(endpoints: &Endpoints) -> OAuthClient {
+        PartialOAuthClient::new(
+            credentials(),
+            endpoints.clone(),
+            CodeVerifier::new(),
+            "http://127.0.0.1/callback".to_owned(),
+        )
+        .exchange_code_for_tokens(AuthzCode::from(format!(
+            "{CODE_PREFIX}{}",
+            Scope::Readonly.as_str()
+        )))
+        .await
+        .unwrap()
+    }
+
+    async fn gmail_client(endpoints: &Endpoints, store: &Store) -> GmailClient {
+        let oauth_client = authorized_client(endpoints).await;
+        store.set_tokens(oauth_client.tokens()).unwrap();
+        GmailClient::new(
+            TokenManager::new(oauth_client, store.clone()),
+            endpoints.gmail.clone(),
+            None,
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn lists_every_page() {
+        let endpoints = start(mailbox(12)).await;
+        let store = Store::open(":memory:").unwrap();
+        let client = gmail_client(&endpoints, &store).await;
+
+        assert_eq!(client.profile().await.unwrap().messages_total, 12);
+        let ids: Vec<_> = client
+            .list_messages(None)
+            .map(|message| message.unwrap().id.to_string())
+            .collect()
+            .await;
+        assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 12);
+    }
+
+    #[tokio::test]
+    async fn retries_injected_failures() {
+        // every second request fails, cycling through all the failure kinds
+        let endpoints = start(FakeConfig {
+            fail_every: Some(2),
+            ..mailbox(3)
+        })
+        .await;
+        let store = Store::open(":memory:").unwrap();
+        let client = gmail_client(&endpoints, &store).await;
+
+        for _ in 0..4 {
+            client.profile().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_a_rejected_access_token() {
+        let endpoints = start(mailbox(3)).await;
+        let store = Store::open(":memory:").unwrap();
+        let tokens = OAuthTokens {
+            access_token: "revoked-access".to_owned().into(),
+            refresh_token: format!("{REFRESH_TOKEN_PREFIX}0").into(),
+            // still valid as far as the client knows
+            expires_at: Utc::now() + TimeDelta::hours(1),
+            refresh_token_expires_at: None,
+            scope: Some(Scope::Readonly.as_str().to_owned()),
+            flow: None,
+        };
+        store.set_tokens(&tokens).unwrap();
+        let oauth_client = OAuthClient::new(credentials(), endpoints.clone(), tokens);
+        let client = GmailClient::new(
+            TokenManager::new(oauth_client, store.clone()),
+            endpoints.gmail.clone(),
+            None,
+            None,
+        );
+
+        client.profile().await.unwrap();
+        let stored = store.load_tokens().unwrap().unwrap();
+        assert!(
+            stored
+                .access_token
+                .as_str()
+                .starts_with(ACCESS_TOKEN_PREFIX)
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_a_revoked_refresh_token() {
+        let endpoints = start(mailbox(3)).await;
+        let mut oauth_client = authorized_client(&endpoints).await;
+        crate::oauth::client::revoke(&endpoints, &oauth_client.tokens().refresh_token)
+            .await
+            .unwrap();
+
+        let err = oauth_client.refresh_access_token().await.err().unwrap();
+        assert!(err.downcast_ref::<InvalidGrant>().is_some(), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn exchanges_a_service_account_assertion() {
+        let endpoints = start(mailbox(3)).await;
+        let key: ServiceAccountKey = serde_json::from_value(json!({
+            "client_email": "archiver@project.iam.gserviceaccount.com",
+            "private_key_id": "test-key",
+            // generated for these tests only
+            "private_key": include_str!("../testdata/service-account-key.pem"),
+            "token_uri": endpoints.token.as_str(),
+        }))
+        .unwrap();
+        let service_account =
+            ServiceAccountClient::new(key, EMAIL_ADDRESS.to_owned(), Scope::Readonly)
+                .await
+                .unwrap();
+        let client = GmailClient::new(
+            TokenManager::service_account(service_account),
+            endpoints.gmail.clone(),
+            None,
+            None,
+        );
+
+        let profile = client.profile().await.unwrap();
+        assert_eq!(profile.email_address, EMAIL_ADDRESS);
+    }
+
+    #[tokio::test]
+    async fn classifies_missing_messages() {
+        let endpoints = start(mailbox(3)).await;
+        let store = Store::open(":memory:").unwrap();
+        let client = gmail_client(&endpoints, &store).await;
+
+        let missing = MessageId::from("0000000000000000".to_owned());
+        let err = client.trash_message(&missing).await.err().unwrap();
+        assert!(
+            is_failure::<DefaultError>(&err, FailureKind::NotFound),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn syncs_the_whole_mailbox() {
+        let endpoints = start(FakeConfig {
+            fail_every: Some(7),
+            ..mailbox(12)
+        })
+        .await;
+        let store = Store::open(":memory:").unwrap();
+        let client = gmail_client(&endpoints, &store).await;
+        let cancel = CancellationToken::new();
+
+        sync::fetch_everything(
+            &client,
+            &store,
+            &SyncConfig::default(),
+            ProgressFormat::Auto,
+            &cancel,
+        )
+        .await
+        .unwrap();
+        let status = store.archive_status().unwrap();
+        assert_eq!(status.messages, 12);
+        assert_eq!(status.raw_messages, 12);
+        // messages 0, 5 and 10 have one
+        assert_eq!(status.attachments, 3);
+        assert_eq!(status.partial_messages, 0);
+        sync::check(&client, &store, None).await.unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! Parsing of the common message headers into structured values, as stored
+//! in the `message_headers` and `message_addresses` tables.
+
+use crate::{mime, model::Header};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressField {
+    From,
+    To,
+    Cc,
+    Bcc,
+    ReplyTo,
+}
+
+impl AddressField {
+    const ALL: [(&str, AddressField); 5] = [
+        ("from", AddressField::From),
+        ("to", AddressField::To),
+        ("cc", AddressField::Cc),
+        ("bcc", AddressField::Bcc),
+        ("reply-to", AddressField::ReplyTo),
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressField::From => "from",
+            AddressField::To => "to",
+            AddressField::Cc => "cc",
+            AddressField::Bcc => "bcc",
+            AddressField::ReplyTo => "reply_to",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub display_name: Option<String>,
+    /// Lowercased, since that's how every mail system we care about
+    /// compares them anyway.
+    pub address: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ParsedHeaders {
+    pub subject: Option<String>,
+    pub date: Option<DateTime<Utc>>,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub addresses: Vec<(AddressField, Address)>,
+}
+
+impl ParsedHeaders {
+    pub fn parse(headers: &[Header]) -> Self {
+        let mut parsed = Self::default();
+        for header in headers {
+            let name = header.name.to_ascii_lowercase();
+            let value = header.value.as_str();
+            match name.as_str() {
+                "subject" => parsed.subject = Some(mime::decode_header(value)),
+                "date" => parsed.date = parse_date(value),
+                "message-id" => parsed.message_id = parse_message_ids(value).into_iter().next(),
+                "in-reply-to" => parsed.in_reply_to = parse_message_ids(value).into_iter().next(),
+                "references" => parsed.references = parse_message_ids(value),
+                name => {
+                    if let Some((_, field)) = AddressField::ALL.iter().find(|(n, _)| *n == name) {
+                        parsed.addresses.extend(
+                            parse_address_list(value)
+                                .into_iter()
+                                .map(|address| (*field, address)),
+                        );
+                    }
+                }
+            }
+        }
+        parsed
+    }
+}
+
+/// Parses an RFC 2822 date, tolerating the trailing comments and missing
+/// seconds that are common in the wild.
+pub fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = match value.find('(') {
+        Some(comment) => &value[..comment],
+        None => value,
+    };
+    let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+    DateTime::parse_from_rfc2822(&value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Extracts the `<...>` identifiers from `Message-ID`, `In-Reply-To` and
+/// `References`, without the angle brackets.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let id = rest[start + 1..start + len].trim();
+        if !id.is_empty() {
+            ids.push(id.to_owned());
+        }
+        rest = &rest[start + len + 1..];
+    }
+    if ids.is_empty() && !value.trim().is_empty() && !value.contains(char::is_whitespace) {
+        // some mailers omit the brackets
+        ids.push(value.trim().to_owned());
+    }
+    ids
+}
+
+/// Parses an address list such as `"Doe, Jane" <jane@example.com>, bob@example.com`.
+/// Group syntax is flattened into its members, and entries without an `@`
+/// (e.g. `undisclosed-recipients:;`) are dropped.
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    let value = strip_comments(value);
+    let mut addresses = Vec::new();
+    for entry in split_entries(&value) {
+        // group syntax: `name: member, member;`
+        let entry = match entry.split_once(':') {
+            Some((_, members)) if !entry.trim_start().starts_with('"') => members,
+            _ => entry,
+        };
+        let entry = entry.trim().trim_end_matches(';').trim();
+        if let Some(address) = parse_address(entry) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+fn parse_address(entry: &str) -> Option<Address> {
+    let (display_name, address) = match (entry.rfind('<'), entry.rfind('>')) {
+        (Some(start), Some(end)) if start < end => (&entry[..start], &entry[start + 1..end]),
+        _ => ("", entry),
+    };
+    let address = address.trim();
+    if !address.contains('@') {
+        return None;
+    }
+    let display_name = display_name.trim();
+    let display_name = display_name
+        .strip_prefix('"')
+        .and_then(|n| n.strip_suffix('"'))
+        .unwrap_or(display_name)
+        .replace("\\\"", "\"");
+    let display_name = mime::decode_header(&display_name);
+    Some(Address {
+        display_name: Some(display_name.trim().to_owned()).filter(|n| !n.is_empty()),
+        address: address.to_lowercase(),
+    })
+}
+
+/// Splits on commas outside of quotes and angle brackets.
+fn split_entries(value: &str) -> Vec<&str> {
+    let mut entries = Vec::new();
+    let mut in_quotes = false;
+    let mut in_brackets = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_brackets = true,
+            '>' if !in_quotes => in_brackets = false,
+            ',' | ';' if !in_quotes && !in_brackets => {
+                entries.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    entries.push(&value[start..]);
+    entries
+}
+
+fn strip_comments(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut depth = 0usize;
+    let mut in_quotes = false;
+    for c in value.chars() {
+        match c {
+            '"' if depth == 0 => {
+                in_quotes = !in_quotes;
+                out.push(c);
+            }
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes && depth > 0 => depth -= 1,
+            _ if depth == 0 => out.push(c),
+            _ => (),
+        }
+    }
+    out
+}
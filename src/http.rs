@@ -1,57 +1,229 @@
-use crate::oauth::AccessToken;
+use crate::{
+    metrics::{self, METRICS},
+    oauth::AccessToken,
+};
 use backoff::ExponentialBackoff;
 use bon::bon;
+use cassette::{Cassette, RequestKey};
+use chrono::{DateTime, Utc};
 use core::fmt;
+use error::DefaultError;
 use eyre::Context;
-use reqwest::{Method, Request, StatusCode, Url};
+use reqwest::{Method, Request, Response, StatusCode, Url, header::RETRY_AFTER};
 use serde::de::DeserializeOwned;
-use std::marker::PhantomData;
-
-// mod error {
-//     use serde::Deserialize;
-
-//     #[derive(Debug, Deserialize)]
-//     pub struct DefaultError {
-//         error: ErrorDetails,
-//     }
-
-//     #[derive(Debug, Deserialize)]
-//     pub struct ErrorDetails {
-//         code: u16,
-//         message: String,
-//         errors: Vec<GranularError>,
-//         status: String,
-//     }
-
-//     #[derive(Debug, Deserialize)]
-//     pub struct GranularError {
-//         message: String,
-//         domain: ErrorDomain,
-//         reason: ErrorReason,
-//     }
-
-//     #[derive(Debug, Deserialize)]
-//     #[serde(rename_all = "camelCase")]
-//     pub enum ErrorDomain {
-//         Global,
-//     }
-
-//     #[derive(Debug, Deserialize)]
-//     #[serde(rename_all = "camelCase")]
-//     pub enum ErrorReason {
-//         RateLimitExceeded,
-//     }
-
-//     #[derive(Debug, Deserialize)]
-//     #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
-//     pub enum Status {
-//         ResourceExhausted,
-//     }
-// }
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+pub mod cassette;
+
+pub mod error {
+    use serde::Deserialize;
+
+    /// The error envelope of Google APIs.
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    pub struct DefaultError {
+        pub error: ErrorDetails,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    pub struct ErrorDetails {
+        pub code: u16,
+        pub message: String,
+        #[serde(default)]
+        pub errors: Vec<GranularError>,
+        pub status: Option<Status>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug, Deserialize)]
+    pub struct GranularError {
+        pub message: String,
+        pub domain: ErrorDomain,
+        pub reason: ErrorReason,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub enum ErrorDomain {
+        Global,
+        UsageLimits,
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "camelCase")]
+    pub enum ErrorReason {
+        RateLimitExceeded,
+        UserRateLimitExceeded,
+        BackendError,
+        NotFound,
+        BadRequest,
+        InvalidArgument,
+        FailedPrecondition,
+        #[serde(other)]
+        Other,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum Status {
+        ResourceExhausted,
+        InvalidArgument,
+        NotFound,
+        PermissionDenied,
+        Unauthenticated,
+        FailedPrecondition,
+        Internal,
+        Unavailable,
+        #[serde(other)]
+        Other,
+    }
+
+    impl DefaultError {
+        pub fn is_rate_limit(&self) -> bool {
+            self.error.status == Some(Status::ResourceExhausted)
+                || self.error.errors.iter().any(|e| {
+                    matches!(
+                        e.reason,
+                        ErrorReason::RateLimitExceeded | ErrorReason::UserRateLimitExceeded
+                    )
+                })
+        }
+    }
+}
+
+/// How a failed request should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Rate limits and server errors, retried with backoff.
+    Transient,
+    /// The request is malformed; retrying won't help.
+    BadRequest,
+    /// The resource doesn't exist, e.g. a message deleted after it was
+    /// listed.
+    NotFound,
+    /// Any other failure, not retried.
+    Other,
+}
+
+impl FailureKind {
+    /// Google reports per-user rate limits as 403 rather than 429, so the
+    /// body must be looked at too.
+    fn classify(status: StatusCode, body: &str) -> Self {
+        match status {
+            StatusCode::TOO_MANY_REQUESTS => FailureKind::Transient,
+            status if status.is_server_error() => FailureKind::Transient,
+            StatusCode::FORBIDDEN
+                if serde_json::from_str::<DefaultError>(body)
+                    .is_ok_and(|error| error.is_rate_limit()) =>
+            {
+                FailureKind::Transient
+            }
+            StatusCode::BAD_REQUEST => FailureKind::BadRequest,
+            StatusCode::NOT_FOUND => FailureKind::NotFound,
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// A failed request, which callers can downcast to. `payload` is the body
+/// parsed as the client's error type, if it could be.
+#[derive(Debug)]
+pub struct ResponseError<E: fmt::Debug> {
+    pub status: StatusCode,
+    pub kind: FailureKind,
+    pub payload: Option<E>,
+    body: String,
+}
+
+impl<E: fmt::Debug + DeserializeOwned> ResponseError<E> {
+    fn new(status: StatusCode, body: String) -> Self {
+        Self {
+            status,
+            kind: FailureKind::classify(status, &body),
+            payload: serde_json::from_str(&body).ok(),
+            body,
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for ResponseError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.payload {
+            Some(payload) => write!(
+                f,
+                "request failed with status {}\n\n{payload:?}",
+                self.status
+            ),
+            None => write!(
+                f,
+                "request failed with status {}: {}",
+                self.status, self.body
+            ),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for ResponseError<E> {}
+
+/// Whether `err` is a failed request of the given kind.
+pub fn is_failure<E: fmt::Debug + Send + Sync + 'static>(
+    err: &eyre::Report,
+    kind: FailureKind,
+) -> bool {
+    err.downcast_ref::<ResponseError<E>>()
+        .is_some_and(|err| err.kind == kind)
+}
+
+/// The access token was rejected, e.g. because it expired in flight or was
+/// revoked. Unlike other failures it's worth retrying with a new token.
+#[derive(Debug, thiserror::Error)]
+#[error("request unauthorized: {0}")]
+pub struct Unauthorized(String);
+
+/// A response, read in full so that it can be recorded or replayed.
+pub struct Exchange {
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: Vec<u8>,
+}
+
+/// Spaces requests evenly to stay under a rate, across all the clients
+/// sharing it.
+pub struct RateLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn per_second(rate: f64) -> eyre::Result<Self> {
+        eyre::ensure!(
+            rate.is_finite() && rate > 0.0,
+            "invalid request rate: {rate}"
+        );
+        Ok(Self {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: tokio::sync::Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Waits for the next free slot. Waiters are served in order since the
+    /// lock is held while sleeping.
+    async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        tokio::time::sleep_until(*next).await;
+        *next = Instant::now().max(*next) + self.interval;
+    }
+}
 
 pub struct GenericClient<E = ()> {
     base_url: Url,
     http_client: reqwest::Client,
+    cassette: Option<Arc<Cassette>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
     _error: PhantomData<E>,
 }
 
@@ -60,6 +232,8 @@ impl<E> Clone for GenericClient<E> {
         Self {
             base_url: self.base_url.clone(),
             http_client: self.http_client.clone(),
+            cassette: self.cassette.clone(),
+            rate_limiter: self.rate_limiter.clone(),
             _error: Default::default(),
         }
     }
@@ -71,10 +245,13 @@ impl<E> GenericClient<E> {
     pub fn new(
         #[builder(start_fn)] base_url: Url,
         #[builder(default)] http_client: reqwest::Client,
+        cassette: Option<Arc<Cassette>>,
     ) -> Self {
         Self {
             base_url,
             http_client,
+            cassette,
+            rate_limiter: None,
             _error: Default::default(),
         }
     }
@@ -83,6 +260,8 @@ impl<E> GenericClient<E> {
         GenericClient {
             base_url: self.base_url.clone(),
             http_client: self.http_client.clone(),
+            cassette: self.cassette.clone(),
+            rate_limiter: self.rate_limiter.clone(),
             _error: Default::default(),
         }
     }
@@ -90,20 +269,42 @@ impl<E> GenericClient<E> {
     pub fn with_base_url(&self, base_url: Url) -> Self {
         Self {
             base_url,
-            http_client: self.http_client.clone(),
-            _error: Default::default(),
+            ..self.clone()
         }
     }
+
+    /// Records exchanges to, or replays them from, `cassette`.
+    pub fn with_cassette(&self, cassette: Option<Arc<Cassette>>) -> Self {
+        Self {
+            cassette,
+            ..self.clone()
+        }
+    }
+
+    /// Holds requests back to stay under the rate of `rate_limiter`.
+    pub fn with_rate_limiter(&self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        Self {
+            rate_limiter,
+            ..self.clone()
+        }
+    }
+
+    fn replaying(&self) -> Option<&Cassette> {
+        self.cassette
+            .as_deref()
+            .filter(|cassette| cassette.is_replay())
+    }
 }
 
 #[bon]
-impl<E: DeserializeOwned + fmt::Debug> GenericClient<E> {
+impl<E: DeserializeOwned + fmt::Debug + Send + Sync + 'static> GenericClient<E> {
     #[builder(finish_fn = send)]
     pub async fn request<T: DeserializeOwned>(
         &self,
         #[builder(start_fn)] path: impl IntoIterator<Item = &str>,
         #[builder(default = Method::GET)] method: Method,
         form: Option<&[(&str, &str)]>,
+        json: Option<&serde_json::Value>,
         query: Option<&[(&str, &str)]>,
         access_token: Option<AccessToken>,
     ) -> eyre::Result<T> {
@@ -120,6 +321,9 @@ impl<E: DeserializeOwned + fmt::Debug> GenericClient<E> {
         if let Some(form) = form {
             request_builder = request_builder.form(form);
         }
+        if let Some(json) = json {
+            request_builder = request_builder.json(json);
+        }
         if let Some(query) = query {
             request_builder = request_builder.query(query);
         }
@@ -131,15 +335,16 @@ impl<E: DeserializeOwned + fmt::Debug> GenericClient<E> {
     // a different quota cost, so it probably makes sense to group requests by
     // type and have a separate bucket for each group
     pub async fn make_request<T: DeserializeOwned>(&self, request: Request) -> eyre::Result<T> {
-        #[derive(Debug, thiserror::Error)]
-        enum Error {
-            #[error(transparent)]
-            Reqwest(#[from] reqwest::Error),
-            #[error("{0}")]
-            Generic(String),
-        }
-
-        let response = backoff::future::retry(ExponentialBackoff::default(), move || {
+        // there's no server to spare when replaying, so retries are immediate
+        let policy = match self.replaying() {
+            Some(_) => ExponentialBackoff {
+                initial_interval: Duration::ZERO,
+                max_interval: Duration::ZERO,
+                ..Default::default()
+            },
+            None => ExponentialBackoff::default(),
+        };
+        let exchange = backoff::future::retry(policy, move || {
             let request = request.try_clone().expect("no stream");
             async move {
                 tracing::debug!(
@@ -148,50 +353,98 @@ impl<E: DeserializeOwned + fmt::Debug> GenericClient<E> {
                 headers = ?request.headers(),
                 "executing request");
 
-                let response = self
-                    .http_client
+                let exchange = self
                     .execute(request)
                     .await
-                    .map_err(|err| backoff::Error::permanent(err.into()))?;
+                    .map_err(backoff::Error::permanent)?;
 
-                if response.status() == StatusCode::TOO_MANY_REQUESTS {
-                    let text = response.text().await.map_err(Error::Reqwest)?;
-                    return Err(backoff::Error::transient(Error::Generic(text)));
+                let status = exchange.status;
+                if status.is_success() {
+                    return Ok(exchange);
                 }
-
-                Ok(response)
+                let retry_after = exchange.retry_after.filter(|_| self.replaying().is_none());
+                let body = String::from_utf8_lossy(&exchange.body).into_owned();
+                if status == StatusCode::UNAUTHORIZED {
+                    return Err(backoff::Error::permanent(eyre::Report::from(Unauthorized(
+                        body,
+                    ))));
+                }
+                let err = ResponseError::<E>::new(status, body);
+                if err.kind != FailureKind::Transient {
+                    return Err(backoff::Error::permanent(err.into()));
+                }
+                tracing::warn!(%status, ?retry_after, "request failed, will retry");
+                METRICS.retries.inc();
+                if matches!(
+                    status,
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::FORBIDDEN
+                ) {
+                    METRICS.rate_limited.inc();
+                }
+                Err(match retry_after {
+                    Some(duration) => backoff::Error::retry_after(err.into(), duration),
+                    None => backoff::Error::transient(err.into()),
+                })
             }
         })
         .await?;
 
-        let status = response.status();
-        if !status.is_success() {
-            let bytes = match response.bytes().await {
-                Ok(bytes) => bytes,
-                Err(_) => {
-                    eyre::bail!("request failed with status {status}");
-                }
-            };
-            let text = match String::from_utf8(bytes.into()) {
-                Ok(text) => text,
-                Err(err) => {
-                    eyre::bail!(
-                        "request failed with status {status}.\nPayload: {:?}",
-                        err.into_bytes()
-                    );
-                }
-            };
-            let payload = match serde_json::from_str::<E>(&text) {
-                Ok(payload) => payload,
-                Err(_) => {
-                    eyre::bail!("request failed with status {status}: {text}");
-                }
-            };
-            eyre::bail!("request failed with status {status}\n\n{payload:?}",);
+        let text = str::from_utf8(&exchange.body)
+            .wrap_err_with(|| format!("raw body: {:?}", exchange.body))?;
+        // some endpoints answer with an empty body, which is only valid for
+        // types that accept `null`
+        let text = if text.trim().is_empty() { "null" } else { text };
+        serde_json::from_str(text).wrap_err_with(|| format!("unexpected payload: {text}"))
+    }
+
+    /// Sends `request`, or looks up its response when replaying a cassette.
+    async fn execute(&self, request: Request) -> eyre::Result<Exchange> {
+        let endpoint = metrics::endpoint(request.url());
+        let timer = METRICS
+            .request_duration
+            .with_label_values(&[&endpoint])
+            .start_timer();
+        let exchange = self.execute_inner(request).await?;
+        timer.observe_duration();
+        METRICS
+            .requests
+            .with_label_values(&[endpoint.as_str(), exchange.status.as_str()])
+            .inc();
+        METRICS.bytes.inc_by(exchange.body.len() as u64);
+        Ok(exchange)
+    }
+
+    async fn execute_inner(&self, request: Request) -> eyre::Result<Exchange> {
+        let key = self.cassette.as_ref().map(|_| RequestKey::new(&request));
+        if let (Some(cassette), Some(key)) = (self.replaying(), &key) {
+            return cassette.find(key);
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.wait().await;
         }
+        let response = self.http_client.execute(request).await?;
+        let exchange = Exchange {
+            status: response.status(),
+            retry_after: retry_after(&response),
+            body: response
+                .bytes()
+                .await
+                .wrap_err("failed to read body")?
+                .to_vec(),
+        };
+        if let (Some(cassette), Some(key)) = (&self.cassette, key) {
+            cassette.save(key, &exchange)?;
+        }
+        Ok(exchange)
+    }
+}
 
-        let data = response.bytes().await.wrap_err("empty body")?;
-        let text = str::from_utf8(&data).wrap_err_with(|| format!("raw body: {data:?}"))?;
-        serde_json::from_str(text).wrap_err_with(|| format!("unexpected payload: {text}"))
+/// Reads `Retry-After`, which holds either a number of seconds or a date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
     }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    (at.with_timezone(&Utc) - Utc::now()).to_std().ok()
 }
@@ -0,0 +1,308 @@
+//! Recording of HTTP exchanges to a file, and their replay, so that a sync
+//! failing on some response can be reproduced offline.
+//!
+//! A cassette holds one JSON object per line. Secrets are redacted before
+//! anything is written: the `Authorization` header isn't recorded at all,
+//! and token values are masked in form bodies, query strings and JSON
+//! responses.
+
+use super::Exchange;
+use eyre::{Context, eyre};
+use reqwest::{Request, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+const REDACTED: &str = "REDACTED";
+
+/// Fields of JSON responses whose values are never recorded.
+const SECRET_FIELDS: &[&str] = &["access_token", "refresh_token", "id_token"];
+
+/// Same for query and form parameters.
+const SECRET_PARAMS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "code",
+    "code_verifier",
+    "client_secret",
+    "assertion",
+    "token",
+    "key",
+];
+
+pub enum Cassette {
+    Record(Mutex<BufWriter<File>>),
+    /// Recorded responses by request, in recording order, so that retried
+    /// requests get the same sequence of responses.
+    Replay(Mutex<HashMap<RequestKey, VecDeque<Exchange>>>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Interaction {
+    #[serde(flatten)]
+    request: RequestKey,
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+    response: String,
+}
+
+/// A request with its secrets redacted, which is how it's matched when
+/// replaying.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RequestKey {
+    method: String,
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+}
+
+impl RequestKey {
+    pub fn new(request: &Request) -> Self {
+        let mut url = request.url().clone();
+        if let Some(query) = url.query().map(redact_pairs) {
+            url.set_query(Some(&query));
+        }
+        Self {
+            method: request.method().to_string(),
+            url: url.into(),
+            body: request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .map(|body| redact_pairs(&String::from_utf8_lossy(body))),
+        }
+    }
+}
+
+impl Cassette {
+    /// Starts a new cassette at `path`, replacing any existing one.
+    pub fn record(path: &Path) -> eyre::Result<Self> {
+        let file = File::create(path)
+            .wrap_err_with(|| format!("failed to create cassette {}", path.display()))?;
+        Ok(Self::Record(Mutex::new(BufWriter::new(file))))
+    }
+
+    pub fn replay(path: &Path) -> eyre::Result<Self> {
+        let file = File::open(path)
+            .wrap_err_with(|| format!("failed to open cassette {}", path.display()))?;
+        let mut exchanges = HashMap::<_, VecDeque<_>>::new();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let interaction: Interaction = serde_json::from_str(&line)
+                .wrap_err_with(|| format!("invalid interaction on line {}", n + 1))?;
+            exchanges
+                .entry(interaction.request)
+                .or_default()
+                .push_back(Exchange {
+                    status: StatusCode::from_u16(interaction.status)?,
+                    retry_after: interaction.retry_after.map(Duration::from_secs),
+                    body: interaction.response.into_bytes(),
+                });
+        }
+        tracing::info!(requests = exchanges.len(), "replaying {}", path.display());
+        Ok(Self::Replay(Mutex::new(exchanges)))
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self, Cassette::Replay(_))
+    }
+
+    /// Appends an exchange, flushing right away so that the cassette is
+    /// complete up to the failure even if the process dies.
+    pub fn save(&self, request: RequestKey, exchange: &Exchange) -> eyre::Result<()> {
+        let Cassette::Record(writer) = self else {
+            return Ok(());
+        };
+        let interaction = Interaction {
+            request,
+            status: exchange.status.as_u16(),
+            retry_after: exchange.retry_after.map(|d| d.as_secs()),
+            response: redact_json(&String::from_utf8_lossy(&exchange.body)),
+        };
+        let mut writer = writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &interaction)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn find(&self, request: &RequestKey) -> eyre::Result<Exchange> {
+        let Cassette::Replay(exchanges) = self else {
+            eyre::bail!("cassette is recording, not replaying");
+        };
+        exchanges
+            .lock()
+            .unwrap()
+            .get_mut(request)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| {
+                eyre!(
+                    "no recorded response for {} {}",
+                    request.method,
+                    request.url
+                )
+            })
+    }
+}
+
+/// Redacts secrets in a query string or form body. Values of other
+/// parameters are kept as they are, still encoded.
+fn redact_pairs(pairs: &str) -> String {
+    pairs
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => format!("{name}={REDACTED}"),
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Redacts secret fields of a JSON body. Bodies without any, which are all
+/// Gmail responses, are kept byte for byte.
+fn redact_json(body: &str) -> String {
+    fn redact(value: &mut Value) -> bool {
+        match value {
+            Value::Object(map) => {
+                let mut redacted = false;
+                for (name, value) in map.iter_mut() {
+                    if SECRET_FIELDS.contains(&name.as_str()) {
+                        *value = Value::String(REDACTED.to_owned());
+                        redacted = true;
+                    } else {
+                        redacted |= redact(value);
+                    }
+                }
+                redacted
+            }
+            Value::Array(values) => values.iter_mut().fold(false, |acc, v| redact(v) | acc),
+            _ => false,
+        }
+    }
+
+    if let Ok(mut value) = serde_json::from_str::<Value>(body)
+        && redact(&mut value)
+    {
+        return value.to_string();
+    }
+    body.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::{Client, Method};
+
+    const SECRETS: &[&str] = &[
+        "client-secret-value",
+        "refresh-token-value",
+        "access-token-value",
+        "new-refresh-token-value",
+        "query-token-value",
+    ];
+
+    fn token_request(client_secret: &str, refresh_token: &str) -> Request {
+        Client::new()
+            .request(Method::POST, "https://oauth2.googleapis.com/token")
+            .form(&[
+                ("client_id", "client-id"),
+                ("client_secret", client_secret),
+                ("refresh_token", refresh_token),
+                ("grant_type", "refresh_token"),
+            ])
+            .build()
+            .unwrap()
+    }
+
+    fn message_request(access_token: &str) -> Request {
+        Client::new()
+            .get("https://gmail.googleapis.com/gmail/v1/users/me/messages/abc")
+            .query(&[("format", "raw"), ("access_token", access_token)])
+            .build()
+            .unwrap()
+    }
+
+    fn exchange(body: &str) -> Exchange {
+        Exchange {
+            status: StatusCode::OK,
+            retry_after: None,
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn records_and_replays_without_secrets() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.jsonl", std::process::id()));
+        let cassette = Cassette::record(&path).unwrap();
+        cassette
+            .save(
+                RequestKey::new(&token_request("client-secret-value", "refresh-token-value")),
+                &exchange(
+                    r#"{"access_token":"access-token-value","expires_in":3599,
+                    "refresh_token":"new-refresh-token-value","token_type":"Bearer"}"#,
+                ),
+            )
+            .unwrap();
+        cassette
+            .save(
+                RequestKey::new(&message_request("query-token-value")),
+                &exchange(r#"{"id":"abc","raw":"SGVsbG8"}"#),
+            )
+            .unwrap();
+        drop(cassette);
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        for secret in SECRETS {
+            assert!(!recorded.contains(secret), "{secret} in {recorded}");
+        }
+        assert!(recorded.contains("grant_type=refresh_token"));
+        assert!(recorded.contains("format=raw"));
+
+        // requests with other secrets have the same redacted key
+        let cassette = Cassette::replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let token = cassette
+            .find(&RequestKey::new(&token_request(
+                "other-secret",
+                "other-token",
+            )))
+            .unwrap();
+        let token: Value = serde_json::from_slice(&token.body).unwrap();
+        assert_eq!(token["access_token"], REDACTED);
+        assert_eq!(token["expires_in"], 3599);
+        let message = cassette
+            .find(&RequestKey::new(&message_request("other-token")))
+            .unwrap();
+        assert_eq!(message.body, br#"{"id":"abc","raw":"SGVsbG8"}"#);
+        // each recorded response is replayed once
+        assert!(
+            cassette
+                .find(&RequestKey::new(&message_request("other-token")))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn keeps_bodies_without_secrets() {
+        let body = r#"{ "messages": [{"id": "a"}], "resultSizeEstimate": 1 }"#;
+        assert_eq!(redact_json(body), body);
+        assert_eq!(
+            redact_json(r#"{"nested":[{"refresh_token":"x"}]}"#),
+            r#"{"nested":[{"refresh_token":"REDACTED"}]}"#
+        );
+        assert_eq!(
+            redact_pairs("code=abc&state=xyz"),
+            "code=REDACTED&state=xyz"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Import of mbox archives, such as the ones produced by Google Takeout, into
+//! the store. Takeout records the Gmail message and thread ids, so imported
+//! messages line up with the ones fetched through the API.
+
+use crate::{
+    mime::{self, Body, Entity},
+    model::{FullMessage, MessagePart, MessagePartBody, PartId},
+    store::Store,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+const SNIPPET_LEN: usize = 200;
+
+/// Takeout pseudo-labels that don't correspond to anything in Gmail.
+const IGNORED_LABELS: [&str; 2] = ["Opened", "Archived"];
+
+pub fn import(store: &Store, path: &Path) -> eyre::Result<()> {
+    let mut reader = MboxReader::new(BufReader::new(File::open(path)?));
+    let (mut imported, mut existing) = (0, 0);
+    while let Some(entry) = reader.next_entry()? {
+        let message = build_message(store, &entry)?;
+        if store.contains_message(&message.id)? {
+            existing += 1;
+        } else {
+            store.insert_message(&message)?;
+            imported += 1;
+        }
+        if !store.contains_raw_message(&message.id)? {
+            store.insert_raw_message(&message.id, &entry.raw)?;
+        }
+        if (imported + existing) % 1000 == 0 {
+            tracing::info!("processed {}K messages", (imported + existing) / 1000);
+        }
+    }
+    tracing::info!("imported {imported} messages, {existing} were already stored");
+    Ok(())
+}
+
+struct MboxEntry {
+    separator: String,
+    raw: Vec<u8>,
+}
+
+struct MboxReader<R> {
+    reader: R,
+    /// The separator line of the next entry, already consumed.
+    next_separator: Option<String>,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            next_separator: None,
+        }
+    }
+
+    fn next_entry(&mut self) -> eyre::Result<Option<MboxEntry>> {
+        let mut line = Vec::new();
+        let separator = match self.next_separator.take() {
+            Some(separator) => separator,
+            None => loop {
+                line.clear();
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(None);
+                }
+                if line.starts_with(b"From ") {
+                    break String::from_utf8_lossy(&line).trim_end().to_owned();
+                }
+            },
+        };
+        let mut raw = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if line.starts_with(b"From ") && (raw.is_empty() || raw.ends_with(b"\n\n")) {
+                self.next_separator = Some(String::from_utf8_lossy(&line).trim_end().to_owned());
+                break;
+            }
+            // mboxrd: `>From ` with any number of `>` loses one of them
+            let quotes = line.iter().take_while(|&&b| b == b'>').count();
+            if quotes > 0 && line[quotes..].starts_with(b"From ") {
+                raw.extend_from_slice(&line[1..]);
+            } else {
+                raw.extend_from_slice(&line);
+            }
+        }
+        // the blank line before the next separator belongs to the mbox format
+        if raw.ends_with(b"\n\n") {
+            raw.pop();
+        }
+        Ok(Some(MboxEntry { separator, raw }))
+    }
+}
+
+fn build_message(store: &Store, entry: &MboxEntry) -> eyre::Result<FullMessage> {
+    let entity = Entity::parse(&entry.raw);
+    let id = separator_id(&entry.separator).unwrap_or_else(|| synthetic_id(&entry.raw));
+    // without Gmail's thread id, every message is a thread of its own
+    let thread_id = entity
+        .header("x-gm-thrid")
+        .and_then(|thread_id| decimal_to_hex(thread_id.trim()))
+        .unwrap_or_else(|| id.clone())
+        .into();
+    let mut label_ids = Vec::new();
+    for name in entity
+        .header("x-gmail-labels")
+        .map(mime::decode_header)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty() && !IGNORED_LABELS.contains(name))
+    {
+        label_ids.push(store.resolve_label(name)?);
+    }
+    let internal_date = separator_date(&entry.separator)
+        .or_else(|| entity.header("date").and_then(crate::headers::parse_date))
+        .unwrap_or_default();
+
+    Ok(FullMessage {
+        id: id.into(),
+        thread_id,
+        label_ids,
+        snippet: snippet(&entity),
+        history_id: String::new().into(),
+        internal_date,
+        size_estimate: entry.raw.len(),
+        payload: build_part(&entity, String::new()),
+    })
+}
+
+/// Mirrors the Gmail API's representation: the root part has an empty id,
+/// and children are numbered with their parent's id as prefix.
+fn build_part(entity: &Entity, part_id: String) -> MessagePart {
+    let parts = match &entity.body {
+        Body::Multipart(children) => children
+            .iter()
+            .enumerate()
+            .map(|(i, child)| {
+                let child_id = if part_id.is_empty() {
+                    i.to_string()
+                } else {
+                    format!("{part_id}.{i}")
+                };
+                build_part(child, child_id)
+            })
+            .collect(),
+        Body::Single(_) => Vec::new(),
+    };
+    let data = entity.decoded_body();
+    MessagePart {
+        part_id: PartId::from(part_id),
+        mime_type: entity.content_type().mime_type,
+        filename: entity.filename().unwrap_or_default(),
+        headers: entity.headers.clone(),
+        body: MessagePartBody {
+            size: data.as_ref().map_or(0, Vec::len),
+            attachment_id: None,
+            data,
+        },
+        parts,
+    }
+}
+
+fn snippet(entity: &Entity) -> String {
+    let text = entity
+        .walk()
+        .into_iter()
+        .filter(|e| e.filename().is_none())
+        .find_map(|e| {
+            let content_type = e.content_type();
+            let data = e.decoded_body()?;
+            let text = mime::decode_charset(&data, content_type.param("charset"));
+            match content_type.mime_type.as_str() {
+                "text/plain" => Some(text.into_owned()),
+                "text/html" => Some(mime::html_to_text(&text)),
+                _ => None,
+            }
+        })
+        .unwrap_or_default();
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(SNIPPET_LEN)
+        .collect()
+}
+
+/// Takeout separators look like `From 1234567890123456789@xxx Wed Jan 01
+/// 00:00:00 +0000 2020`, with the message id in decimal.
+fn separator_id(separator: &str) -> Option<String> {
+    let sender = separator.strip_prefix("From ")?.split_whitespace().next()?;
+    decimal_to_hex(sender.strip_suffix("@xxx")?)
+}
+
+fn separator_date(separator: &str) -> Option<DateTime<Utc>> {
+    let date = separator
+        .strip_prefix("From ")?
+        .split_whitespace()
+        .skip(1)
+        .filter(|part| !part.starts_with(['+', '-']))
+        .collect::<Vec<_>>()
+        .join(" ");
+    NaiveDateTime::parse_from_str(&date, "%a %b %e %T %Y")
+        .ok()
+        .map(|date| date.and_utc())
+}
+
+fn decimal_to_hex(decimal: &str) -> Option<String> {
+    decimal.parse::<u64>().ok().map(|id| format!("{id:x}"))
+}
+
+/// Messages from other sources have no Gmail id, so one is derived from
+/// their content to keep repeated imports idempotent.
+fn synthetic_id(raw: &[u8]) -> String {
+    let hash = Sha256::digest(raw);
+    let hex: String = hash[..8].iter().map(|b| format!("{b:02x}")).collect();
+    format!("mbox-{hex}")
+}
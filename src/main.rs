@@ -1,24 +1,253 @@
+mod auth;
 mod client;
+mod config;
+mod contacts;
+mod endpoints;
+mod export;
+mod fake;
+mod format;
+mod headers;
 mod http;
+mod import;
 mod macros;
+mod metrics;
+mod mime;
 mod model;
 mod oauth;
+mod progress;
+mod query;
+mod retention;
+mod search;
+mod serve;
+mod show;
+mod shutdown;
+mod stats;
 mod store;
+mod sync;
+mod watch;
 
-use clap::Parser;
+use chrono::Utc;
+use clap::{Parser, Subcommand};
 use client::GmailClient;
-use model::{AttachmentId, FullMessage, MessageId};
-use oauth::{ClientCredentials, TokenManager, client::OAuthClient};
-use std::path::PathBuf;
+use config::Config;
+use endpoints::Endpoints;
+use format::format_size;
+use http::{RateLimiter, cassette::Cassette};
+use oauth::{
+    AuthorizationFlow, ClientCredentials, InvalidGrant, Scope, TokenManager,
+    client::OAuthClient,
+    service_account::{ServiceAccountClient, ServiceAccountKey},
+};
+use reqwest::Url;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use store::Store;
-use tokio_stream::StreamExt;
-use tracing::Level;
+
+/// Exit status when the stored authorization is no longer valid and can't
+/// be renewed without user interaction, so that scripts can tell it apart
+/// from other failures.
+const EXIT_REAUTHORIZATION_REQUIRED: i32 = 3;
+/// How many sync runs `status` lists.
+const RECENT_SYNC_RUNS: usize = 5;
+
+/// Used unless the database is set on the command line or in the config.
+const DEFAULT_DB: &str = "data.db";
 
 #[derive(Parser)]
 struct Args {
-    secrets_file: PathBuf,
-    #[arg(long, default_value = "data.db")]
-    db: PathBuf,
+    /// TOML file with defaults for the options below, and settings of
+    /// accounts and export targets
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_CONFIG")]
+    config: Option<PathBuf>,
+    /// Account of the config file to use, instead of its top-level settings
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_ACCOUNT")]
+    account: Option<String>,
+    /// Database file [default: data.db]
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_DB")]
+    db: Option<PathBuf>,
+    /// OAuth client secrets file downloaded from the Google Cloud console,
+    /// needed by commands that talk to Gmail
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_SECRETS_FILE")]
+    secrets_file: Option<PathBuf>,
+    /// Service account key with domain-wide delegation, used instead of
+    /// the user authorization flow
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_SERVICE_ACCOUNT")]
+    service_account: Option<PathBuf>,
+    /// Workspace user whose mailbox the service account accesses
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_IMPERSONATE")]
+    impersonate: Option<String>,
+    /// Send Gmail and OAuth requests to this server instead of Google, e.g.
+    /// one started with `fake-server`
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_API_URL")]
+    api_url: Option<Url>,
+    /// Most Gmail requests sent per second, unlimited by default
+    #[arg(long, global = true, env = "GMAIL_ARCHIVER_REQUESTS_PER_SECOND")]
+    requests_per_second: Option<f64>,
+    /// Record Gmail requests and responses to this cassette file, with
+    /// tokens redacted
+    #[arg(long, global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Serve Gmail responses from a cassette file recorded with `--record`
+    /// instead of contacting the server, e.g. to rerun a failed sync offline
+    /// against an empty database
+    #[arg(long, global = true)]
+    replay: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage authorization to the Gmail account
+    Auth {
+        #[command(subcommand)]
+        command: auth::AuthCommand,
+    },
+    /// Download labels, messages, attachments and raw messages
+    Sync {
+        #[arg(long, value_enum, default_value_t = progress::ProgressFormat::Auto)]
+        progress: progress::ProgressFormat,
+        /// Serve Prometheus metrics at `/metrics` on this address while
+        /// syncing
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+        /// Keep running after the first sync, syncing again on a schedule
+        /// until interrupted
+        #[arg(long)]
+        watch: bool,
+        /// Time between the start of syncs in watch mode, e.g. `15m` or `2h`
+        #[arg(long, requires = "watch", default_value = "15m", value_parser = humantime::parse_duration)]
+        interval: Duration,
+        /// Schedule syncs in watch mode with a cron expression instead,
+        /// e.g. `0 */6 * * *`, in UTC. Days of the week are numbered as in
+        /// crontab, from 0 for Sunday
+        #[arg(long, requires = "watch", conflicts_with = "interval", value_parser = watch::parse_cron)]
+        cron: Option<Box<cron::Schedule>>,
+        /// In watch mode, keep this file updated with the state of the loop,
+        /// as JSON, at least every minute
+        #[arg(long, requires = "watch")]
+        heartbeat: Option<PathBuf>,
+        #[command(flatten)]
+        selection: SyncSelection,
+        /// Messages fetched at the same time [default: 1]
+        #[arg(long, env = "GMAIL_ARCHIVER_CONCURRENCY")]
+        concurrency: Option<usize>,
+    },
+    /// Compare the remote mailbox with the store without downloading
+    /// messages, failing if anything is missing
+    Check {
+        #[command(flatten)]
+        selection: SyncSelection,
+    },
+    /// Remove old mail from Gmail once it's archived, according to rules
+    Retention {
+        #[command(subcommand)]
+        command: retention::RetentionCommand,
+    },
+    /// Summarize the contents of the store, how fresh it is and recent
+    /// syncs
+    Status,
+    /// Export raw messages, optionally restricted by a search query
+    Export {
+        /// Export target of the config file, providing defaults for the
+        /// options below
+        #[arg(long)]
+        target: Option<String>,
+        /// [default: mbox]
+        #[arg(long, value_enum)]
+        format: Option<export::ExportFormat>,
+        /// Output file for mbox, directory for eml
+        #[arg(long, short, required_unless_present = "target")]
+        output: Option<PathBuf>,
+        /// Search query selecting the messages, everything if omitted
+        query: Vec<String>,
+    },
+    /// Import messages from an mbox file, such as a Google Takeout export
+    Import { path: PathBuf },
+    /// Search stored messages using Gmail search syntax
+    Search {
+        /// Search query, e.g. `from:alice has:attachment after:2020/01/01`
+        #[arg(required = true)]
+        query: Vec<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// Print a stored message: headers, text body and attachments
+    Show { id: String },
+    /// Parse the headers of stored messages again into the header and
+    /// address tables
+    RebuildHeaders,
+    /// Build the address book of correspondents from message headers
+    Contacts {
+        /// Export the address book to this file in vCard format instead of
+        /// listing it
+        #[arg(long)]
+        vcard: Option<PathBuf>,
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Report mailbox statistics
+    Stats {
+        /// Granularity of the volume over time
+        #[arg(long, value_enum, default_value_t = stats::Period::Month)]
+        period: stats::Period,
+        /// Number of entries in the top senders, domains, etc. lists
+        #[arg(long, default_value_t = 20)]
+        top: usize,
+        #[arg(long, value_enum, default_value_t = stats::Format::Table)]
+        format: stats::Format,
+    },
+    /// Browse the archive in a web browser
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: SocketAddr,
+    },
+    /// Run a fake Gmail and OAuth server over a generated mailbox, for
+    /// testing and demos without a Google account
+    FakeServer {
+        #[arg(long, default_value = "127.0.0.1:8025")]
+        bind: SocketAddr,
+        /// Number of messages in the mailbox
+        #[arg(long, default_value_t = 500)]
+        messages: usize,
+        /// Messages per page when listing, unless the client asks otherwise
+        #[arg(long, default_value_t = 100)]
+        page_size: usize,
+        /// Fail every Nth Gmail request with a rate limit or server error
+        #[arg(long)]
+        fail_every: Option<u64>,
+    },
+}
+
+/// Which messages of the mailbox are synced, all of them by default.
+#[derive(Clone, clap::Args)]
+struct SyncSelection {
+    /// Only sync messages with any of these labels, by name or id
+    #[arg(long = "include-label", value_name = "LABEL")]
+    include_labels: Vec<String>,
+    /// Don't sync messages with any of these labels
+    #[arg(long = "exclude-label", value_name = "LABEL")]
+    exclude_labels: Vec<String>,
+    /// Only sync messages matching this Gmail search query
+    #[arg(long)]
+    filter: Option<String>,
+}
+
+impl SyncSelection {
+    /// Overrides the settings of the config file with those given.
+    fn or(self, config: config::SyncConfig) -> config::SyncConfig {
+        let non_empty = |labels: Vec<String>| (!labels.is_empty()).then_some(labels);
+        config::SyncConfig {
+            include_labels: non_empty(self.include_labels).or(config.include_labels),
+            exclude_labels: non_empty(self.exclude_labels).or(config.exclude_labels),
+            query: self.filter.or(config.query),
+            ..config
+        }
+    }
 }
 
 fn setup_logging() {
@@ -34,111 +263,376 @@ async fn main() -> eyre::Result<()> {
     let args = Args::parse();
     setup_logging();
 
-    let store = Store::open(args.db)?;
-    let creds = ClientCredentials::load_from_file(args.secrets_file)?;
+    let result = run(args).await;
+    if let Err(err) = &result {
+        if err.downcast_ref::<InvalidGrant>().is_some() {
+            eprintln!("Error: {err}");
+            std::process::exit(EXIT_REAUTHORIZATION_REQUIRED);
+        }
+        if err.downcast_ref::<shutdown::Interrupted>().is_some() {
+            eprintln!("{err}");
+            std::process::exit(shutdown::EXIT_INTERRUPTED);
+        }
+    }
+    result
+}
+
+async fn run(mut args: Args) -> eyre::Result<()> {
+    if let Command::FakeServer {
+        bind,
+        messages,
+        page_size,
+        fail_every,
+    } = args.command
+    {
+        let config = fake::FakeConfig {
+            messages,
+            page_size,
+            fail_every: fail_every.filter(|&n| n > 0),
+        };
+        return fake::serve(bind, config).await;
+    }
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let sync_config = apply_config(&mut args, &config)?;
+    let store = Store::open(args.db.as_deref().unwrap_or(Path::new(DEFAULT_DB)))?;
+    match args.command {
+        Command::Auth { command } => match command {
+            auth::AuthCommand::Login {
+                headless,
+                port,
+                scope,
+            } => {
+                let creds = load_credentials(&args)?;
+                auth::login(&store, creds, endpoints(&args)?, headless, port, scope).await
+            }
+            auth::AuthCommand::Status => auth::status(&store),
+            auth::AuthCommand::Revoke => auth::revoke(&store, &endpoints(&args)?).await,
+        },
+        Command::Sync {
+            progress,
+            metrics,
+            watch,
+            interval,
+            ref cron,
+            ref heartbeat,
+            ref selection,
+            concurrency,
+        } => {
+            if let Some(bind) = metrics {
+                metrics::spawn_server(bind).await?;
+            }
+            let sync_config = config::SyncConfig {
+                concurrency: concurrency.or(sync_config.concurrency),
+                ..selection.clone().or(sync_config)
+            };
+            let cancel = shutdown::on_signal();
+            let client = gmail_client(&args, &store, Scope::Readonly).await?;
+            if !watch {
+                return sync::fetch_everything(&client, &store, &sync_config, progress, &cancel)
+                    .await;
+            }
+            let schedule = match cron {
+                Some(cron) => watch::Schedule::Cron(cron.clone()),
+                None => watch::Schedule::Interval(interval),
+            };
+            watch::watch(
+                &client,
+                &store,
+                &sync_config,
+                progress,
+                &cancel,
+                &schedule,
+                heartbeat.clone(),
+            )
+            .await
+        }
+        Command::Check { ref selection } => {
+            let filter = selection.clone().or(sync_config).filter();
+            let client = gmail_client(&args, &store, Scope::Readonly).await?;
+            sync::check(&client, &store, filter).await
+        }
+        Command::Retention { ref command } => match command {
+            retention::RetentionCommand::Plan { rules } => {
+                retention::plan(&store, retention::load_rules(rules)?)?.print();
+                Ok(())
+            }
+            retention::RetentionCommand::Apply { rules, yes } => {
+                let plan = retention::plan(&store, retention::load_rules(rules)?)?;
+                plan.print();
+                let Some(scope) = plan.scope() else {
+                    println!("nothing to remove");
+                    return Ok(());
+                };
+                let deletions = plan.permanent_deletions();
+                eyre::ensure!(
+                    deletions == 0 || *yes,
+                    "this would permanently delete {deletions} messages from Gmail, \
+                    pass --yes to confirm"
+                );
+                let cancel = shutdown::on_signal();
+                let client = gmail_client(&args, &store, scope).await?;
+                retention::apply(&client, &store, &plan, &cancel).await
+            }
+        },
+        Command::Status => status(&store),
+        Command::Export {
+            ref target,
+            format,
+            ref output,
+            ref query,
+        } => {
+            let target = target
+                .as_deref()
+                .map(|name| config.export_target(name))
+                .transpose()?;
+            let format = format
+                .or(target.and_then(|target| target.format))
+                .unwrap_or(export::ExportFormat::Mbox);
+            // the target's output, if there's no flag
+            let output = output
+                .as_ref()
+                .or(target.map(|target| &target.output))
+                .expect("required without a target");
+            let query = match target.and_then(|target| target.query.clone()) {
+                Some(target_query) if query.is_empty() => vec![target_query],
+                _ => query.clone(),
+            };
+            export::export(&store, format, output, &query)
+        }
+        Command::Import { path } => import::import(&store, &path),
+        Command::Search { query, limit } => search(&store, &query, limit),
+        Command::Show { id } => show::show(&store, &id.into()),
+        Command::RebuildHeaders => rebuild_headers(&store),
+        Command::Contacts { vcard, limit } => contacts::contacts(&store, vcard.as_deref(), limit),
+        Command::Stats {
+            period,
+            top,
+            format,
+        } => stats::stats(&store, period, top, format),
+        Command::Serve { bind } => serve::serve(store, bind).await,
+        Command::FakeServer { .. } => unreachable!("handled before opening the store"),
+    }
+}
+
+/// Completes `args` with the settings of the config file, returning those
+/// of the selected account's syncs.
+fn apply_config(args: &mut Args, config: &Config) -> eyre::Result<config::SyncConfig> {
+    let account = config.account(args.account.as_deref())?;
+    args.db = args.db.take().or(account.db);
+    args.secrets_file = args.secrets_file.take().or(account.secrets_file);
+    args.service_account = args.service_account.take().or(account.service_account);
+    args.impersonate = args.impersonate.take().or(account.impersonate);
+    if args.api_url.is_none() {
+        args.api_url = config.api_url()?;
+    }
+    args.requests_per_second = args
+        .requests_per_second
+        .or(account.sync.requests_per_second);
+    eyre::ensure!(
+        args.service_account.is_some() == args.impersonate.is_some(),
+        "a service account and the user to impersonate go together"
+    );
+    Ok(account.sync)
+}
+
+fn endpoints(args: &Args) -> eyre::Result<Endpoints> {
+    match &args.api_url {
+        Some(url) => {
+            eyre::ensure!(!url.cannot_be_a_base(), "invalid --api-url: {url}");
+            Ok(Endpoints::under(url))
+        }
+        None => Ok(Endpoints::default()),
+    }
+}
+
+fn load_credentials(args: &Args) -> eyre::Result<ClientCredentials> {
+    let Some(secrets_file) = &args.secrets_file else {
+        eyre::bail!("--secrets-file is required to access Gmail");
+    };
+    ClientCredentials::load_from_file(secrets_file)
+}
+
+/// Uses the service account if one is given. Otherwise uses the stored
+/// tokens, running the authorization flow if there are none or if they don't
+/// grant `scope`. No authorization is needed to replay a cassette.
+async fn gmail_client(args: &Args, store: &Store, scope: Scope) -> eyre::Result<GmailClient> {
+    let endpoints = endpoints(args)?;
+    if let Some(path) = &args.replay {
+        let cassette = Arc::new(Cassette::replay(path)?);
+        return Ok(GmailClient::new(
+            TokenManager::replay(),
+            endpoints.gmail,
+            Some(cassette),
+            None,
+        ));
+    }
+    let rate_limiter = args
+        .requests_per_second
+        .map(RateLimiter::per_second)
+        .transpose()?
+        .map(Arc::new);
+    let cassette = match &args.record {
+        Some(path) => Some(Arc::new(Cassette::record(path)?)),
+        None => None,
+    };
+    if let (Some(key), Some(subject)) = (&args.service_account, &args.impersonate) {
+        let key = ServiceAccountKey::load_from_file(key)?;
+        let client = ServiceAccountClient::new(key, subject.clone(), scope).await?;
+        tracing::info!(subject, "using service account delegation");
+        let token_manager = TokenManager::service_account(client);
+        return Ok(GmailClient::new(
+            token_manager,
+            endpoints.gmail,
+            cassette,
+            rate_limiter,
+        ));
+    }
+    let creds = load_credentials(args)?;
     let oauth_client = match store.load_tokens()? {
-        Some(tokens) => {
+        Some(tokens) if scope.is_granted(tokens.scope.as_deref()) => {
             tracing::info!("tokens loaded from database");
-            OAuthClient::new(creds, tokens)
+            OAuthClient::new(creds, endpoints.clone(), tokens)
         }
-        None => {
-            tracing::info!("no tokens in database, initiating authorization flow");
-            let oauth_client = OAuthClient::authorize(creds).await?;
+        tokens => {
+            if tokens.is_some() {
+                tracing::info!(
+                    ?scope,
+                    "stored tokens lack the required access, reauthorizing"
+                );
+            } else {
+                tracing::info!("no tokens in database, initiating authorization flow");
+            }
+            let flow = AuthorizationFlow::Browser { port: None };
+            let oauth_client =
+                OAuthClient::authorize(flow, creds, endpoints.clone(), scope).await?;
             tracing::info!("authorization flow successful");
             store.set_tokens(oauth_client.tokens())?;
             oauth_client
         }
     };
     let token_manager = TokenManager::new(oauth_client, store.clone());
-    let client = GmailClient::new(token_manager);
-    fetch_everything(&client, &store).await?;
-
-    Ok(())
-}
-
-async fn fetch_everything(client: &GmailClient, store: &Store) -> eyre::Result<()> {
-    fetch_labels(client, store).await?;
-    fetch_messages(client, store).await?;
-    Ok(())
+    Ok(GmailClient::new(
+        token_manager,
+        endpoints.gmail,
+        cassette,
+        rate_limiter,
+    ))
 }
 
-async fn fetch_labels(client: &GmailClient, store: &Store) -> eyre::Result<()> {
-    let labels = client.list_labels().await?;
-    tracing::info!("processing {} labels", labels.labels.len());
-    for label in labels.labels {
-        if store.contains_label(&label.id)? {
-            tracing::debug!(id = %label.id, "label already stored");
-            continue;
-        }
-        tracing::debug!(id = %label.id, "fetching label from remote");
-        let label = client.label(&label.id).await?;
-        store.insert_label(&label)?;
-        tracing::debug!(id = %label.id, "label stored successfully");
+fn status(store: &Store) -> eyre::Result<()> {
+    let status = store.archive_status()?;
+    println!("messages:      {}", status.messages);
+    println!("raw messages:  {}", status.raw_messages);
+    println!("labels:        {}", status.labels);
+    println!(
+        "attachments:   {} ({})",
+        status.attachments,
+        format_size(status.attachment_bytes)
+    );
+    println!("mailbox size:  {}", format_size(status.size_estimate));
+    if status.partial_messages > 0 {
+        println!(
+            "partial:       {} messages, run `sync` to complete them",
+            status.partial_messages
+        );
+    }
+    if let (Some(oldest), Some(newest)) = (status.oldest, status.newest) {
+        println!(
+            "date range:    {} - {}",
+            oldest.format("%Y-%m-%d"),
+            newest.format("%Y-%m-%d")
+        );
+    }
+    match store.load_tokens()? {
+        Some(_) => println!("authorized:    yes"),
+        None => println!("authorized:    no, run `auth login`"),
     }
-    Ok(())
-}
 
-async fn fetch_messages(client: &GmailClient, store: &Store) -> eyre::Result<()> {
-    let profile = client.profile().await?;
-    let total = profile.messages_total;
-    let stored = store.message_count()?;
-    tracing::info!("total messages: {total}, stored: {stored}");
-    let mut fetched = 0;
-    let mut messages = client.list_messages();
-    while let Some(message) = messages.next().await.transpose()? {
-        if store.contains_message(&message.id)? {
-            tracing::debug!(id = %message.id, "message already stored");
-            for attachment_id in store.attachment_ids(&message.id)? {
-                fetch_attachment(client, store, &message.id, &attachment_id).await?;
-            }
-        } else {
-            let message = client.full_message(&message.id).await?;
-            store.insert_message(&message)?;
-            tracing::debug!(id = %message.id, "message stored successfully");
-            for attachment_id in extract_attachment_ids(&message) {
-                fetch_attachment(client, store, &message.id, attachment_id).await?;
+    match store.last_successful_sync_run()? {
+        Some(run) => {
+            let finished = run.finished_at.unwrap_or(run.started_at);
+            println!(
+                "last sync:     {} ({} ago){}",
+                finished.format("%Y-%m-%d %H:%M"),
+                format_age(Utc::now() - finished),
+                run.counts
+                    .account
+                    .map(|account| format!(", {account}"))
+                    .unwrap_or_default()
+            );
+            if let Some(history_id) = run.counts.history_id {
+                println!("history id:    {history_id}");
             }
         }
-        if store.contains_raw_message(&message.id)? {
-            tracing::debug!(id = %message.id, "raw message already stored");
-        } else {
-            let raw_message = client.raw_message(&message.id).await?;
-            store.insert_raw_message(&message.id, &raw_message.raw)?;
-            tracing::debug!(id = %message.id, "raw message stored successfully");
+        None => println!("last sync:     never, run `sync`"),
+    }
+    let runs = store.sync_runs(RECENT_SYNC_RUNS)?;
+    if let Some(latest) = runs.first() {
+        if latest.finished_at.is_none() {
+            println!("warning:       the latest sync is still running or was interrupted");
+        } else if latest.error.is_some() {
+            println!("warning:       the latest sync failed");
         }
-        fetched += 1;
-        if fetched % 1000 == 0 {
-            tracing::info!(total, "fetched {}K messages", fetched / 1000);
+    }
+    if !runs.is_empty() {
+        println!("\nrecent syncs:");
+    }
+    for run in runs {
+        let duration = match run.finished_at {
+            Some(finished) => format!("{}s", (finished - run.started_at).num_seconds()),
+            None => "-".to_owned(),
+        };
+        println!(
+            "  #{:<4} {}  {:<5} {:>7}  +{} ~{} -{}  {} attachments  {}  {} errors",
+            run.id,
+            run.started_at.format("%Y-%m-%d %H:%M"),
+            run.mode,
+            duration,
+            run.counts.messages_added,
+            run.counts.messages_updated,
+            run.counts.messages_deleted,
+            run.counts.attachments,
+            format_size(run.counts.bytes as usize),
+            run.counts.errors,
+        );
+        if let Some(error) = run.error.as_deref().and_then(|error| error.lines().next()) {
+            println!("    {error}");
         }
     }
     Ok(())
 }
 
-#[tracing::instrument(level = Level::DEBUG, skip_all, fields(msg_id = %message_id, id = %attachment_id))]
-async fn fetch_attachment(
-    client: &GmailClient,
-    store: &Store,
-    message_id: &MessageId,
-    attachment_id: &AttachmentId,
-) -> eyre::Result<()> {
-    if store.contains_message_attachment(message_id, attachment_id)? {
-        tracing::debug!("attachment already stored");
-    } else {
-        let attachment = client.attachment(message_id, attachment_id).await?;
-        store.insert_attachment(message_id, attachment_id, &attachment)?;
-        tracing::debug!("attachment stored succesfully");
+fn format_age(age: chrono::TimeDelta) -> String {
+    match (age.num_days(), age.num_hours(), age.num_minutes()) {
+        (0, 0, minutes) => format!("{minutes}m"),
+        (0, hours, _) => format!("{hours}h"),
+        (days, _, _) => format!("{days}d"),
     }
-    Ok(())
 }
 
-fn extract_attachment_ids(message: &FullMessage) -> Vec<&AttachmentId> {
-    let mut attachments = Vec::new();
-    let mut parts = Vec::from([&message.payload]);
-    while let Some(part) = parts.pop() {
-        if let Some(attachment_id) = &part.body.attachment_id {
-            attachments.push(attachment_id);
+fn search(store: &Store, query: &[String], limit: usize) -> eyre::Result<()> {
+    let query = query::compile(&query::parse(&query.join(" "))?);
+    for hit in store.search(&query, limit)? {
+        println!(
+            "{}  {}  {:>6.2}  {}",
+            hit.message_id,
+            hit.internal_date.format("%Y-%m-%d"),
+            hit.score,
+            hit.subject.as_deref().unwrap_or("(no subject)"),
+        );
+        println!("    {}", hit.from.as_deref().unwrap_or("(unknown sender)"));
+        if let Some(snippet) = hit.snippet.filter(|s| !s.is_empty()) {
+            println!("    {snippet}");
         }
-        parts.extend(part.parts.iter());
     }
-    attachments
+    Ok(())
+}
+
+fn rebuild_headers(store: &Store) -> eyre::Result<()> {
+    let count = store.rebuild_headers()?;
+    tracing::info!("parsed headers of {count} messages");
+    Ok(())
 }
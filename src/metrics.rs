@@ -0,0 +1,200 @@
+//! Prometheus metrics of the process, optionally served over HTTP so that
+//! long syncs can be monitored, and alerted on when they stall.
+
+use axum::{Router, http::header, response::IntoResponse, routing::get};
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+use reqwest::Url;
+use std::{
+    net::SocketAddr,
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::net::TcpListener;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Path segments kept as they are in the `endpoint` label; others are ids.
+const ENDPOINT_SEGMENTS: &[&str] = &[
+    "gmail",
+    "v1",
+    "users",
+    "me",
+    "profile",
+    "labels",
+    "messages",
+    "attachments",
+    "trash",
+    "batchDelete",
+    "token",
+    "revoke",
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// By endpoint and status.
+    pub requests: IntCounterVec,
+    /// By endpoint.
+    pub request_duration: HistogramVec,
+    pub retries: IntCounter,
+    /// 429s, and 403s reporting a rate limit.
+    pub rate_limited: IntCounter,
+    pub bytes: IntCounter,
+    /// By kind: label, message, raw_message or attachment.
+    pub stored: IntCounterVec,
+    pub last_stored: Gauge,
+    pub token_refreshes: IntCounter,
+    /// By kind, same as `stored`.
+    pub db_write_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("gmail_archiver".to_owned()), None).expect("valid prefix");
+        let register = |collector: Box<dyn prometheus::core::Collector>| {
+            registry.register(collector).expect("unique metric");
+        };
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "http_requests_total",
+                "HTTP requests by endpoint and status",
+            ),
+            &["endpoint", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["endpoint"],
+        )
+        .unwrap();
+        let retries =
+            IntCounter::new("http_retries_total", "Failed requests retried with backoff").unwrap();
+        let rate_limited = IntCounter::new(
+            "http_rate_limited_total",
+            "Requests rejected because of a rate limit",
+        )
+        .unwrap();
+        let bytes =
+            IntCounter::new("http_received_bytes_total", "Bytes of response bodies").unwrap();
+        let stored = IntCounterVec::new(
+            Opts::new("stored_total", "Items written to the store by kind"),
+            &["kind"],
+        )
+        .unwrap();
+        let last_stored = Gauge::new(
+            "last_stored_timestamp_seconds",
+            "Unix time of the last write to the store",
+        )
+        .unwrap();
+        let token_refreshes = IntCounter::new(
+            "token_refreshes_total",
+            "Access tokens obtained by refreshing",
+        )
+        .unwrap();
+        let db_write_duration = HistogramVec::new(
+            HistogramOpts::new("db_write_duration_seconds", "Store write latency by kind")
+                .buckets(prometheus::exponential_buckets(0.0005, 2.0, 14).unwrap()),
+            &["kind"],
+        )
+        .unwrap();
+        register(Box::new(requests.clone()));
+        register(Box::new(request_duration.clone()));
+        register(Box::new(retries.clone()));
+        register(Box::new(rate_limited.clone()));
+        register(Box::new(bytes.clone()));
+        register(Box::new(stored.clone()));
+        register(Box::new(last_stored.clone()));
+        register(Box::new(token_refreshes.clone()));
+        register(Box::new(db_write_duration.clone()));
+        Self {
+            registry,
+            requests,
+            request_duration,
+            retries,
+            rate_limited,
+            bytes,
+            stored,
+            last_stored,
+            token_refreshes,
+            db_write_duration,
+        }
+    }
+
+    /// All requests, whatever their endpoint and status.
+    pub fn requests_total(&self) -> u64 {
+        use prometheus::core::Collector;
+        self.requests
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().get_value() as u64)
+            .sum()
+    }
+
+    /// Runs a write to the store of an item of `kind`, timing it and counting
+    /// the item if it succeeds.
+    pub fn store<T>(&self, kind: &str, write: impl FnOnce() -> eyre::Result<T>) -> eyre::Result<T> {
+        let timer = self
+            .db_write_duration
+            .with_label_values(&[kind])
+            .start_timer();
+        let result = write()?;
+        timer.observe_duration();
+        self.stored.with_label_values(&[kind]).inc();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_stored.set(now.as_secs_f64());
+        Ok(result)
+    }
+}
+
+/// The path of `url` with ids replaced by `{id}`, to keep the number of
+/// series bounded.
+pub fn endpoint(url: &Url) -> String {
+    url.path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            if ENDPOINT_SEGMENTS.contains(&segment) {
+                segment
+            } else {
+                "{id}"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Binds the metrics endpoint and serves it in the background, at
+/// `/metrics`.
+pub async fn spawn_server(bind: SocketAddr) -> eyre::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    tracing::info!(
+        "serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    let router = Router::new().route("/metrics", get(render));
+    tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, router).await {
+            tracing::error!("metrics server failed: {err}");
+        }
+    });
+    Ok(())
+}
+
+async fn render() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .expect("text encoding");
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_owned())],
+        buffer,
+    )
+}
@@ -0,0 +1,493 @@
+//! Just enough MIME to read archived messages: header unfolding, RFC 2047
+//! encoded words, multipart bodies, transfer encodings and charsets.
+
+use crate::model::Header;
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+
+pub struct Entity {
+    pub headers: Vec<Header>,
+    pub body: Body,
+}
+
+pub enum Body {
+    /// Still transfer-encoded, as it appears in the message.
+    Single(Vec<u8>),
+    Multipart(Vec<Entity>),
+}
+
+pub struct ContentType {
+    pub mime_type: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Entity {
+    pub fn parse(data: &[u8]) -> Self {
+        let (headers, body) = split_headers(data);
+        let headers = parse_headers(headers);
+        let content_type = content_type(&headers);
+        let boundary = content_type
+            .mime_type
+            .starts_with("multipart/")
+            .then(|| content_type.param("boundary"))
+            .flatten();
+        let body = match boundary {
+            Some(boundary) => Body::Multipart(
+                split_multipart(body, boundary.as_bytes())
+                    .into_iter()
+                    .map(Entity::parse)
+                    .collect(),
+            ),
+            None => Body::Single(body.to_vec()),
+        };
+        Self { headers, body }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        content_type(&self.headers)
+    }
+
+    /// The attachment filename, from either `Content-Disposition` or the
+    /// legacy `name` parameter of `Content-Type`.
+    pub fn filename(&self) -> Option<String> {
+        let disposition = self.header("content-disposition").map(parse_content_type);
+        let content_type = self.content_type();
+        [
+            (disposition.as_ref(), "filename"),
+            (Some(&content_type), "name"),
+        ]
+        .into_iter()
+        .find_map(|(params, name)| {
+            let params = params?;
+            // RFC 2231 extended parameters take precedence
+            let extended = params.param(&format!("{name}*")).map(decode_extended_param);
+            extended.or_else(|| params.param(name).map(decode_header))
+        })
+    }
+
+    /// The body with its transfer encoding removed.
+    pub fn decoded_body(&self) -> Option<Vec<u8>> {
+        let Body::Single(data) = &self.body else {
+            return None;
+        };
+        let encoding = self
+            .header("content-transfer-encoding")
+            .unwrap_or("7bit")
+            .trim()
+            .to_ascii_lowercase();
+        Some(match encoding.as_str() {
+            "base64" => decode_base64_lenient(data),
+            "quoted-printable" => decode_quoted_printable(data),
+            _ => data.clone(),
+        })
+    }
+
+    /// Visits this entity and all of its descendants, depth first.
+    pub fn walk(&self) -> Vec<&Entity> {
+        let mut entities = Vec::new();
+        let mut stack = Vec::from([self]);
+        while let Some(entity) = stack.pop() {
+            entities.push(entity);
+            if let Body::Multipart(children) = &entity.body {
+                stack.extend(children.iter().rev());
+            }
+        }
+        entities
+    }
+}
+
+pub fn header<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+pub fn content_type(headers: &[Header]) -> ContentType {
+    header(headers, "content-type")
+        .map(parse_content_type)
+        .unwrap_or_else(|| ContentType {
+            mime_type: "text/plain".to_owned(),
+            params: Vec::new(),
+        })
+}
+
+pub fn parse_content_type(value: &str) -> ContentType {
+    let mut segments = split_unquoted(value, ';').into_iter();
+    let mime_type = segments
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let params = segments
+        .filter_map(|segment| {
+            let (name, value) = segment.split_once('=')?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            Some((name.trim().to_owned(), value.to_owned()))
+        })
+        .collect();
+    ContentType { mime_type, params }
+}
+
+fn split_unquoted(value: &str, sep: char) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                segments.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => (),
+        }
+    }
+    segments.push(&value[start..]);
+    segments
+}
+
+fn split_headers(data: &[u8]) -> (&[u8], &[u8]) {
+    for (sep, len) in [(&b"\r\n\r\n"[..], 4), (&b"\n\n"[..], 2)] {
+        if let Some(pos) = data.windows(len).position(|w| w == sep) {
+            return (&data[..pos], &data[pos + len..]);
+        }
+    }
+    (data, &[])
+}
+
+fn parse_headers(data: &[u8]) -> Vec<Header> {
+    let text = String::from_utf8_lossy(data);
+    let mut headers: Vec<Header> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some(last) = headers.last_mut() {
+                last.value.push(' ');
+                last.value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push(Header {
+                name: name.trim().to_owned(),
+                value: value.trim().to_owned(),
+            });
+        }
+    }
+    headers
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &[u8]) -> Vec<&'a [u8]> {
+    let delimiter = [b"--", boundary].concat();
+    let mut parts = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut offset = 0;
+    while offset < body.len() {
+        let end = body[offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(body.len(), |pos| offset + pos + 1);
+        let line = trim_line_end(&body[offset..end]);
+        if line.starts_with(&delimiter) {
+            if let Some(start) = current {
+                // the line break before the delimiter belongs to the delimiter
+                parts.push(trim_line_end(&body[start..offset]));
+            }
+            if line[delimiter.len()..].starts_with(b"--") {
+                return parts;
+            }
+            current = Some(end);
+        }
+        offset = end;
+    }
+    if let Some(start) = current {
+        parts.push(&body[start..]);
+    }
+    parts
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+fn decode_base64_lenient(data: &[u8]) -> Vec<u8> {
+    let cleaned: Vec<u8> = data
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    STANDARD
+        .decode(&cleaned)
+        .or_else(|_| {
+            base64::engine::general_purpose::STANDARD_NO_PAD.decode(cleaned.trim_ascii_end())
+        })
+        .unwrap_or(cleaned)
+}
+
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'=' => {
+                let rest = &data[i + 1..];
+                if rest.starts_with(b"\r\n") {
+                    i += 3;
+                } else if rest.starts_with(b"\n") {
+                    i += 2;
+                } else if let Some(byte) = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    out.push(byte);
+                    i += 3;
+                } else {
+                    out.push(b'=');
+                    i += 1;
+                }
+            }
+            byte => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Decodes bytes in the given charset, falling back to UTF-8 for unknown
+/// charsets. Invalid sequences are replaced rather than rejected.
+pub fn decode_charset<'a>(data: &'a [u8], charset: Option<&str>) -> Cow<'a, str> {
+    let encoding = charset
+        .and_then(|label| Encoding::for_label(label.trim().as_bytes()))
+        .unwrap_or(UTF_8);
+    encoding.decode(data).0
+}
+
+/// Decodes an RFC 2231 extended parameter value, e.g. `UTF-8''caf%C3%A9`.
+fn decode_extended_param(value: &str) -> String {
+    let mut segments = value.splitn(3, '\'');
+    let (Some(charset), Some(_language), Some(encoded)) =
+        (segments.next(), segments.next(), segments.next())
+    else {
+        return value.to_owned();
+    };
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    decode_charset(&bytes, Some(charset)).into_owned()
+}
+
+/// Decodes RFC 2047 encoded words, e.g. `=?ISO-8859-1?Q?caf=E9?=`.
+pub fn decode_header(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_encoded_word = false;
+    while let Some(start) = rest.find("=?") {
+        let Some((decoded, len)) = decode_encoded_word(&rest[start..]) else {
+            out.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            after_encoded_word = false;
+            continue;
+        };
+        let between = &rest[..start];
+        // whitespace between adjacent encoded words is not significant
+        if !(after_encoded_word && between.trim().is_empty()) {
+            out.push_str(between);
+        }
+        out.push_str(&decoded);
+        rest = &rest[start + len..];
+        after_encoded_word = true;
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Decodes a single encoded word at the start of `word`, returning the text
+/// and the number of bytes consumed.
+fn decode_encoded_word(word: &str) -> Option<(String, usize)> {
+    let inner = word.strip_prefix("=?")?;
+    let (charset, inner) = inner.split_once('?')?;
+    let (encoding, inner) = inner.split_once('?')?;
+    let end = inner.find("?=")?;
+    let text = &inner[..end];
+    // RFC 2231 allows a language suffix, e.g. UTF-8*en
+    let charset = charset.split('*').next().unwrap_or(charset);
+    let bytes = match encoding {
+        "B" | "b" => decode_base64_lenient(text.as_bytes()),
+        "Q" | "q" => decode_quoted_printable(text.replace('_', " ").as_bytes()),
+        _ => return None,
+    };
+    let consumed = word.len() - inner.len() + end + 2;
+    Some((decode_charset(&bytes, Some(charset)).into_owned(), consumed))
+}
+
+/// Converts HTML to plain text, keeping line breaks for block elements.
+/// Drops tags, comments, and the contents of `<script>` and `<style>`
+/// elements.
+pub fn html_to_text(html: &str) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&decode_entities(&rest[..start]));
+        rest = &rest[start..];
+        let end_marker = if rest.starts_with("<!--") {
+            "-->"
+        } else if starts_with_ignore_case(rest, "<script") {
+            "</script>"
+        } else if starts_with_ignore_case(rest, "<style") {
+            "</style>"
+        } else {
+            ">"
+        };
+        let Some(end) = find_ignore_case(rest, end_marker) else {
+            rest = "";
+            break;
+        };
+        let tag_name: String = rest[1..end]
+            .trim_start_matches('/')
+            .chars()
+            .take_while(char::is_ascii_alphanumeric)
+            .collect::<String>()
+            .to_ascii_lowercase();
+        out.push(if is_block_element(&tag_name) {
+            '\n'
+        } else {
+            ' '
+        });
+        rest = &rest[end + end_marker.len()..];
+    }
+    out.push_str(&decode_entities(rest));
+    collapse_whitespace(&out)
+}
+
+fn is_block_element(name: &str) -> bool {
+    matches!(
+        name,
+        "br" | "p"
+            | "div"
+            | "tr"
+            | "li"
+            | "ul"
+            | "ol"
+            | "table"
+            | "blockquote"
+            | "hr"
+            | "pre"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "section"
+            | "title"
+    )
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank_lines = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank_lines += 1;
+            if blank_lines > 1 || out.is_empty() {
+                continue;
+            }
+        } else {
+            blank_lines = 0;
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+fn starts_with_ignore_case(haystack: &str, prefix: &str) -> bool {
+    haystack
+        .as_bytes()
+        .get(..prefix.len())
+        .is_some_and(|head| head.eq_ignore_ascii_case(prefix.as_bytes()))
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';').filter(|&end| end <= 10) else {
+            out.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let decoded = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            entity => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use crate::macros::{impl_as_str, impl_display};
+use crate::macros::{impl_as_str, impl_display, impl_from_string};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize};
 use std::sync::Arc;
@@ -16,6 +16,7 @@ impl_as_str!(
     AttachmentId
 );
 impl_display!(LabelId, ThreadId, MessageId, PartId, AttachmentId);
+impl_from_string!(MessageId, LabelId, ThreadId, HistoryId, PartId);
 
 pub struct PageParts<T> {
     pub next_page_token: Option<PageToken>,
@@ -137,7 +138,7 @@ pub struct MessagePart {
     pub parts: Vec<MessagePart>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AttachmentId(String);
 
 impl From<String> for AttachmentId {
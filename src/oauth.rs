@@ -1,51 +1,181 @@
 pub mod client;
 mod server;
+pub mod service_account;
 
 use crate::{
+    endpoints::Endpoints,
     http::GenericClient,
     macros::{impl_as_str, impl_from_string},
     store::Store,
 };
 use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use client::OAuthClient;
-use reqwest::Url;
 use secrets_file::SecretsFile;
 use serde::Deserialize;
+use service_account::ServiceAccountClient;
 use sha2::{Digest, Sha256};
 use std::{
     fs::File,
-    io::BufReader,
+    io::{BufReader, IsTerminal},
     path::Path,
-    sync::{Arc, LazyLock},
+    sync::Arc,
 };
 
-pub static TOKEN_ENDPOINT: LazyLock<Url> =
-    LazyLock::new(|| Url::parse("https://oauth2.googleapis.com/token").expect("valid url"));
+/// Gmail access levels, from least to most privileged. Each one includes
+/// the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Scope {
+    /// Read messages, labels and settings, which is all archiving needs
+    Readonly,
+    /// Also change labels on messages
+    Modify,
+    /// Everything, including permanently deleting messages
+    Full,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Readonly => "https://www.googleapis.com/auth/gmail.readonly",
+            Scope::Modify => "https://www.googleapis.com/auth/gmail.modify",
+            Scope::Full => "https://mail.google.com/",
+        }
+    }
+
+    /// The highest level among space-separated granted scopes. Tokens from
+    /// before scopes were recorded always had full access.
+    pub fn granted(scope: Option<&str>) -> Option<Scope> {
+        let Some(scope) = scope else {
+            return Some(Scope::Full);
+        };
+        scope
+            .split_whitespace()
+            .filter_map(|granted| {
+                [Scope::Readonly, Scope::Modify, Scope::Full]
+                    .into_iter()
+                    .find(|s| s.as_str() == granted)
+            })
+            .max()
+    }
+
+    pub fn is_granted(&self, scope: Option<&str>) -> bool {
+        Scope::granted(scope).is_some_and(|granted| granted >= *self)
+    }
+}
+
+/// The refresh token was revoked by the user, or expired (after 7 days for
+/// apps in testing mode). Only a new authorization can recover from it.
+#[derive(Debug, thiserror::Error)]
+#[error("the refresh token was revoked or has expired, run `auth login` to authorize again")]
+pub struct InvalidGrant;
 
 pub struct TokenManager {
-    client: OAuthClient,
-    store: Store,
+    source: TokenSource,
+}
+
+enum TokenSource {
+    /// Tokens granted by the user, persisted in the store.
+    User {
+        client: Box<OAuthClient>,
+        store: Store,
+    },
+    ServiceAccount(Box<ServiceAccountClient>),
+    /// No authorization at all, for replaying a cassette: recorded requests
+    /// are matched without their access token.
+    Replay(AccessToken),
 }
 
 impl TokenManager {
     pub fn new(client: OAuthClient, store: Store) -> Self {
-        Self { client, store }
+        Self {
+            source: TokenSource::User {
+                client: Box::new(client),
+                store,
+            },
+        }
+    }
+
+    pub fn service_account(client: ServiceAccountClient) -> Self {
+        Self {
+            source: TokenSource::ServiceAccount(Box::new(client)),
+        }
+    }
+
+    pub fn replay() -> Self {
+        Self {
+            source: TokenSource::Replay(AccessToken::from("replayed".to_owned())),
+        }
     }
 
     pub fn http_client<E>(&self) -> GenericClient<E> {
-        self.client.http_client()
+        match &self.source {
+            TokenSource::User { client, .. } => client.http_client(),
+            TokenSource::ServiceAccount(client) => client.http_client(),
+            TokenSource::Replay(_) => GenericClient::builder(Endpoints::default().gmail).build(),
+        }
     }
 
+    /// Refreshes the access token if it's about to expire. Callers share the
+    /// manager behind a lock, so concurrent callers wait for the first one's
+    /// refresh instead of each starting their own.
     pub async fn update_access_token(&mut self) -> eyre::Result<()> {
-        if let Some(update) = self.client.check_access_token().await? {
-            tracing::debug!("access token refreshed, will update database");
-            self.store.update_access_token(update)?;
+        self.refresh(false).await
+    }
+
+    /// Refreshes the access token after the API rejected `rejected`, unless
+    /// another caller already replaced it.
+    pub async fn refresh_rejected(&mut self, rejected: &AccessToken) -> eyre::Result<()> {
+        if self.access_token().as_str() != rejected.as_str() {
+            tracing::debug!("rejected access token already replaced");
+            return Ok(());
+        }
+        self.refresh(true).await
+    }
+
+    async fn refresh(&mut self, force: bool) -> eyre::Result<()> {
+        match &mut self.source {
+            TokenSource::User { client, store } => {
+                let result = if force {
+                    client.refresh_access_token().await.map(Some)
+                } else {
+                    client.check_access_token().await
+                };
+                match result {
+                    Ok(Some(update)) => {
+                        tracing::debug!("access token refreshed, will update database");
+                        store.update_access_token(update)?;
+                    }
+                    Ok(None) => (),
+                    Err(err) if err.downcast_ref::<InvalidGrant>().is_some() => {
+                        store.clear_tokens()?;
+                        if !std::io::stdin().is_terminal() {
+                            return Err(err);
+                        }
+                        tracing::warn!("{err}, restarting the authorization flow");
+                        if let Err(err) = client.reauthorize().await {
+                            // retrying won't help until the user runs `auth login`
+                            tracing::error!("authorization failed: {err:#}");
+                            return Err(InvalidGrant.into());
+                        }
+                        store.set_tokens(client.tokens())?;
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            TokenSource::ServiceAccount(client) if force => client.refresh_access_token().await?,
+            TokenSource::ServiceAccount(client) => client.check_access_token().await?,
+            TokenSource::Replay(_) => (),
         }
         Ok(())
     }
 
     pub fn access_token(&self) -> &AccessToken {
-        self.client.access_token()
+        match &self.source {
+            TokenSource::User { client, .. } => client.access_token(),
+            TokenSource::ServiceAccount(client) => client.access_token(),
+            TokenSource::Replay(access_token) => access_token,
+        }
     }
 }
 
@@ -58,7 +188,7 @@ impl_as_str!(
     State
 );
 
-impl_from_string!(AccessToken, RefreshToken);
+impl_from_string!(AccessToken, RefreshToken, AuthzCode);
 
 #[derive(Clone, Deserialize)]
 pub struct ClientId(String);
@@ -163,10 +293,25 @@ pub struct AccessToken(Arc<str>);
 #[derive(Debug, Deserialize)]
 pub struct RefreshToken(String);
 
+/// How the user went through authorization, so that it runs the same way
+/// when the grant has to be renewed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthorizationFlow {
+    /// Opens a browser, with the loopback server on `port`, or on any free
+    /// port if `None`.
+    Browser { port: Option<u16> },
+    /// Reads the redirect URL from stdin.
+    Headless { port: Option<u16> },
+}
+
 #[derive(Debug)]
 pub struct OAuthTokens {
     pub access_token: AccessToken,
     pub refresh_token: RefreshToken,
     pub expires_at: DateTime<Utc>,
     pub refresh_token_expires_at: Option<DateTime<Utc>>,
+    /// Space-separated scopes granted by the user.
+    pub scope: Option<String>,
+    /// Unknown for tokens obtained before flows were recorded.
+    pub flow: Option<AuthorizationFlow>,
 }
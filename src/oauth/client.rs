@@ -1,15 +1,25 @@
 use super::{AuthzCode, ClientCredentials, CodeVerifier, OAuthTokens};
 use crate::{
-    http::GenericClient,
-    oauth::{AccessToken, State, TOKEN_ENDPOINT, server},
+    endpoints::Endpoints,
+    http::{GenericClient, ResponseError},
+    metrics::METRICS,
+    oauth::{
+        AccessToken, AuthorizationFlow, InvalidGrant, RefreshToken, Scope, State,
+        server::{self, CallbackServer},
+    },
 };
 use chrono::{DateTime, Utc};
 use reqwest::{Method, Url};
 use serde::Deserialize;
 use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
 
-const AUTHZ_ENDPOINT: &str = "https://accounts.google.com/o/oauth2/v2/auth";
-const REDIRECT_URI: &str = "http://127.0.0.1:47218/callback";
+/// Nothing listens on it in the headless flow, so it's only used to build
+/// the redirect URI when no port is given.
+const HEADLESS_PORT: u16 = 47218;
+
+/// How long before its expiry an access token is replaced.
+pub const REFRESH_MARGIN: Duration = Duration::from_secs(2 * 60);
 
 #[allow(dead_code)]
 #[derive(Deserialize, Debug)]
@@ -22,16 +32,25 @@ type HttpClient = GenericClient<ErrorResponse>;
 
 pub struct PartialOAuthClient {
     creds: ClientCredentials,
+    endpoints: Endpoints,
     http_client: HttpClient,
     verifier: CodeVerifier,
+    redirect_uri: String,
 }
 
 impl PartialOAuthClient {
-    pub fn new(creds: ClientCredentials, verifier: CodeVerifier) -> Self {
+    pub fn new(
+        creds: ClientCredentials,
+        endpoints: Endpoints,
+        verifier: CodeVerifier,
+        redirect_uri: String,
+    ) -> Self {
         Self {
-            http_client: GenericClient::builder(TOKEN_ENDPOINT.clone()).build(),
+            http_client: GenericClient::builder(endpoints.token.clone()).build(),
             creds,
+            endpoints,
             verifier,
+            redirect_uri,
         }
     }
 
@@ -42,7 +61,7 @@ impl PartialOAuthClient {
             expires_in: u64,
             refresh_token: String,
             refresh_token_expires_in: Option<u64>,
-            // scope: String,
+            scope: Option<String>,
         }
 
         let resp = self
@@ -54,7 +73,7 @@ impl PartialOAuthClient {
                 ("code_verifier", self.verifier.as_str()),
                 ("client_id", self.creds.id.as_str()),
                 ("client_secret", self.creds.secret.as_str()),
-                ("redirect_uri", REDIRECT_URI),
+                ("redirect_uri", self.redirect_uri.as_str()),
                 ("grant_type", "authorization_code"),
             ])
             .send()
@@ -72,12 +91,15 @@ impl PartialOAuthClient {
         );
         Ok(OAuthClient {
             creds: self.creds.clone(),
+            endpoints: self.endpoints.clone(),
             http_client: self.http_client.clone(),
             tokens: OAuthTokens {
                 access_token: resp.access_token.into(),
                 refresh_token: resp.refresh_token.into(),
                 expires_at,
                 refresh_token_expires_at,
+                scope: resp.scope,
+                flow: None,
             },
         })
     }
@@ -85,37 +107,117 @@ impl PartialOAuthClient {
 
 pub struct OAuthClient {
     creds: ClientCredentials,
+    endpoints: Endpoints,
     tokens: OAuthTokens,
     http_client: HttpClient,
 }
 
 impl OAuthClient {
-    pub fn new(creds: ClientCredentials, tokens: OAuthTokens) -> Self {
+    pub fn new(creds: ClientCredentials, endpoints: Endpoints, tokens: OAuthTokens) -> Self {
         Self {
             creds,
             tokens,
-            http_client: GenericClient::builder(TOKEN_ENDPOINT.clone()).build(),
+            http_client: GenericClient::builder(endpoints.token.clone()).build(),
+            endpoints,
         }
     }
 
-    pub async fn authorize(creds: ClientCredentials) -> eyre::Result<Self> {
+    /// Runs `flow`, recording it with the tokens so that reauthorizing runs
+    /// it again.
+    pub async fn authorize(
+        flow: AuthorizationFlow,
+        creds: ClientCredentials,
+        endpoints: Endpoints,
+        scope: Scope,
+    ) -> eyre::Result<Self> {
+        let mut client = match flow {
+            AuthorizationFlow::Browser { port } => {
+                Self::authorize_browser(creds, endpoints, scope, port).await?
+            }
+            AuthorizationFlow::Headless { port } => {
+                Self::authorize_headless(creds, endpoints, scope, port).await?
+            }
+        };
+        client.tokens.flow = Some(flow);
+        Ok(client)
+    }
+
+    /// Runs the authorization flow with a loopback server on `port`, or on
+    /// any free port if `None`.
+    async fn authorize_browser(
+        creds: ClientCredentials,
+        endpoints: Endpoints,
+        scope: Scope,
+        port: Option<u16>,
+    ) -> eyre::Result<Self> {
         let code_verifier = CodeVerifier::new();
         let state = State::new();
-
-        let mut url = Url::parse(AUTHZ_ENDPOINT)?;
-        url.query_pairs_mut()
-            .append_pair("client_id", creds.id.as_str())
-            .append_pair("redirect_uri", REDIRECT_URI)
-            .append_pair("response_type", "code")
-            .append_pair("scope", "https://mail.google.com/")
-            .append_pair("code_challenge", &code_verifier.to_s256())
-            .append_pair("code_challenge_method", "S256")
-            .append_pair("state", state.as_str());
+        let server = CallbackServer::bind(port).await?;
+        let url = authorization_url(
+            &creds,
+            &endpoints,
+            scope,
+            &state,
+            &code_verifier,
+            server.redirect_uri(),
+        )?;
 
         tracing::debug!("opening browser window");
         webbrowser::open(url.as_str())?;
         println!("Authorization URL: {url}");
-        server::wait_response(creds, state, code_verifier).await
+        server
+            .wait_response(creds, endpoints, state, code_verifier)
+            .await
+    }
+
+    /// Authorization for machines without a browser: the user opens the URL
+    /// elsewhere and pastes back the address they were redirected to. That
+    /// page fails to load, since nothing listens on the loopback address, but
+    /// its URL carries the code.
+    async fn authorize_headless(
+        creds: ClientCredentials,
+        endpoints: Endpoints,
+        scope: Scope,
+        port: Option<u16>,
+    ) -> eyre::Result<Self> {
+        let code_verifier = CodeVerifier::new();
+        let state = State::new();
+        let redirect_uri = server::redirect_uri(port.unwrap_or(HEADLESS_PORT));
+        let url = authorization_url(
+            &creds,
+            &endpoints,
+            scope,
+            &state,
+            &code_verifier,
+            &redirect_uri,
+        )?;
+
+        println!("Open this URL in a browser and grant access:\n\n{url}\n");
+        println!(
+            "You will be redirected to a page that fails to load. \
+            Paste its address (or just the `code` parameter) here:"
+        );
+        let mut input = String::new();
+        BufReader::new(tokio::io::stdin())
+            .read_line(&mut input)
+            .await?;
+        let code = parse_redirect(input.trim(), &state)?;
+        PartialOAuthClient::new(creds, endpoints, code_verifier, redirect_uri)
+            .exchange_code_for_tokens(code)
+            .await
+    }
+
+    /// Runs the authorization flow again the way it ran first, for the
+    /// scopes granted so far, replacing the tokens. Tokens from before flows
+    /// were recorded go through the browser.
+    pub async fn reauthorize(&mut self) -> eyre::Result<()> {
+        let scope = Scope::granted(self.tokens.scope.as_deref()).unwrap_or(Scope::Readonly);
+        let flow = self
+            .tokens
+            .flow
+            .unwrap_or(AuthorizationFlow::Browser { port: None });
+        *self = Self::authorize(flow, self.creds.clone(), self.endpoints.clone(), scope).await?;
+        Ok(())
     }
 
     pub fn http_client<E>(&self) -> GenericClient<E> {
@@ -130,7 +232,19 @@ impl OAuthClient {
         &self.tokens.access_token
     }
 
+    /// Refreshes the access token if it expires within `REFRESH_MARGIN`, so
+    /// that requests sent with it don't fail in flight.
     pub async fn check_access_token(&mut self) -> eyre::Result<Option<AccessTokenUpdate>> {
+        if self.tokens.expires_at - REFRESH_MARGIN > Utc::now() {
+            tracing::debug!(expires_at = %self.tokens.expires_at, "access token still valid");
+            return Ok(None);
+        }
+        tracing::info!("access token about to expire, fetching new one");
+        self.refresh_access_token().await.map(Some)
+    }
+
+    /// Unconditionally fetches a new access token.
+    pub async fn refresh_access_token(&mut self) -> eyre::Result<AccessTokenUpdate> {
         #[derive(Deserialize)]
         struct TokenResponse {
             access_token: AccessToken,
@@ -138,12 +252,6 @@ impl OAuthClient {
             // scope: String,
         }
 
-        if self.tokens.expires_at > Utc::now() {
-            tracing::debug!(expires_at = %self.tokens.expires_at, "access token still valid");
-            return Ok(None);
-        }
-        tracing::info!("access token expired, fetching new one");
-
         let TokenResponse {
             access_token,
             expires_in,
@@ -158,19 +266,88 @@ impl OAuthClient {
                 ("grant_type", "refresh_token"),
             ])
             .send()
-            .await?;
+            .await
+            .map_err(
+                |err| match err.downcast_ref::<ResponseError<ErrorResponse>>() {
+                    Some(ResponseError {
+                        payload: Some(payload),
+                        ..
+                    }) if payload.error == "invalid_grant" => InvalidGrant.into(),
+                    _ => err,
+                },
+            )?;
 
         let expires_at = Utc::now() + Duration::from_secs(expires_in);
         tracing::info!("new token expires at {expires_at}");
+        METRICS.token_refreshes.inc();
 
         self.tokens.access_token = access_token.clone();
         self.tokens.expires_at = expires_at;
 
-        Ok(Some(AccessTokenUpdate {
+        Ok(AccessTokenUpdate {
             access_token,
             expires_at,
-        }))
+        })
+    }
+}
+
+/// Revokes the grant behind a refresh token, which also invalidates the
+/// access tokens obtained with it.
+pub async fn revoke(endpoints: &Endpoints, refresh_token: &RefreshToken) -> eyre::Result<()> {
+    HttpClient::builder(endpoints.revoke.clone())
+        .build()
+        .request::<serde::de::IgnoredAny, _>([])
+        .method(Method::POST)
+        .form(&[("token", refresh_token.as_str())])
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Builds the consent screen URL. Previously granted scopes are included in
+/// the new grant, so asking for more access never loses any.
+fn authorization_url(
+    creds: &ClientCredentials,
+    endpoints: &Endpoints,
+    scope: Scope,
+    state: &State,
+    code_verifier: &CodeVerifier,
+    redirect_uri: &str,
+) -> eyre::Result<Url> {
+    let mut url = endpoints.authorization.clone();
+    url.query_pairs_mut()
+        .append_pair("client_id", creds.id.as_str())
+        .append_pair("redirect_uri", redirect_uri)
+        .append_pair("response_type", "code")
+        .append_pair("scope", scope.as_str())
+        .append_pair("include_granted_scopes", "true")
+        .append_pair("code_challenge", &code_verifier.to_s256())
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("state", state.as_str());
+    Ok(url)
+}
+
+/// Extracts the code from a pasted redirect URL, checking its state. A bare
+/// code is accepted as is, since there's no state to check.
+fn parse_redirect(input: &str, state: &State) -> eyre::Result<AuthzCode> {
+    let Ok(url) = Url::parse(input) else {
+        eyre::ensure!(!input.is_empty(), "no authorization code provided");
+        return Ok(AuthzCode::from(input.to_owned()));
+    };
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    if let Some(error) = param("error") {
+        eyre::bail!("authorization failed: {error}");
     }
+    eyre::ensure!(
+        param("state").as_deref() == Some(state.as_str()),
+        "invalid state in redirect URL"
+    );
+    let code = param("code").ok_or_else(|| eyre::eyre!("no code in redirect URL"))?;
+    Ok(AuthzCode::from(code))
 }
 
 pub struct AccessTokenUpdate {
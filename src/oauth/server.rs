@@ -1,42 +1,125 @@
 use super::{AuthzCode, ClientCredentials, CodeVerifier, State as OAuthState, client::OAuthClient};
+use crate::endpoints::Endpoints;
 use axum::{
     Router,
     extract::{Query, State},
+    http::StatusCode,
     response::Html,
     routing::get,
 };
 use error::ServerError;
-use eyre::eyre;
+use eyre::{WrapErr, eyre};
 use maud::html;
 use serde::Deserialize;
 use state::ServerState;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    future::IntoFuture,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 use tokio::{net::TcpListener, sync::oneshot};
 use tokio_util::sync::CancellationToken;
 
-const BIND_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 47218);
+const BIND_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const CALLBACK_PATH: &str = "/callback";
 
-pub async fn wait_response(
-    creds: ClientCredentials,
-    state: OAuthState,
-    verifier: CodeVerifier,
-) -> eyre::Result<OAuthClient> {
-    let token = CancellationToken::new();
-    let (tx, rx) = oneshot::channel();
-    let router = make_router(ServerState::new(creds, state, verifier, token.clone(), tx));
-    let listener = TcpListener::bind(BIND_ADDR).await?;
-    axum::serve(listener, router)
-        .with_graceful_shutdown(shutdown_handler(token))
-        .await?;
-    Ok(rx.await?)
+/// How long to wait for the user to complete the consent screen.
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Loopback server receiving the authorization redirect. It's bound before
+/// the authorization URL is built, so the redirect URI can carry the actual
+/// port, which the OS picks unless one is requested.
+pub struct CallbackServer {
+    listener: TcpListener,
+    redirect_uri: String,
+}
+
+impl CallbackServer {
+    pub async fn bind(port: Option<u16>) -> eyre::Result<Self> {
+        let addr = SocketAddr::new(BIND_IP, port.unwrap_or(0));
+        let listener = TcpListener::bind(addr)
+            .await
+            .wrap_err_with(|| format!("failed to bind authorization callback server to {addr}"))?;
+        let redirect_uri = redirect_uri(listener.local_addr()?.port());
+        Ok(Self {
+            listener,
+            redirect_uri,
+        })
+    }
+
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    pub async fn wait_response(
+        self,
+        creds: ClientCredentials,
+        endpoints: Endpoints,
+        state: OAuthState,
+        verifier: CodeVerifier,
+    ) -> eyre::Result<OAuthClient> {
+        let token = CancellationToken::new();
+        let (tx, rx) = oneshot::channel();
+        let router = make_router(ServerState::new(
+            creds,
+            endpoints,
+            state,
+            verifier,
+            self.redirect_uri,
+            token.clone(),
+            tx,
+        ));
+        let serve = axum::serve(self.listener, router)
+            .with_graceful_shutdown(shutdown_handler(token))
+            .into_future();
+        match tokio::time::timeout(AUTHORIZATION_TIMEOUT, serve).await {
+            Ok(result) => result?,
+            Err(_) => eyre::bail!(
+                "authorization not completed within {} minutes, run `auth login` again \
+                (or `auth login --headless` if no browser is available)",
+                AUTHORIZATION_TIMEOUT.as_secs() / 60
+            ),
+        }
+        Ok(rx.await?)
+    }
+}
+
+/// Loopback redirect URI for a given port. Google accepts any port for
+/// desktop clients.
+pub fn redirect_uri(port: u16) -> String {
+    format!("http://{BIND_IP}:{port}{CALLBACK_PATH}")
 }
 
 fn make_router(state: ServerState) -> Router<()> {
     Router::new()
-        .route("/callback", get(callback))
+        .route(CALLBACK_PATH, get(callback))
+        .fallback(stray_request)
         .with_state(state)
 }
 
+/// Browsers ask for `/favicon.ico`, and users sometimes open the server's
+/// root; neither should look like a failure of the authorization.
+async fn stray_request() -> (StatusCode, Html<String>) {
+    (
+        StatusCode::NOT_FOUND,
+        Html(
+            html! {
+                (maud::DOCTYPE)
+                meta charset="utf-8";
+                title { "Gmail Archiver - Authz" }
+                body {
+                    h1 { "Nothing here" }
+                    p {
+                        "This server only receives the redirect at the end of the authorization. "
+                        "Complete the consent screen in the window opened by the archiver."
+                    }
+                }
+            }
+            .into_string(),
+        ),
+    )
+}
+
 // when this future completes, shutdown starts
 async fn shutdown_handler(token: CancellationToken) {
     token.cancelled().await
@@ -91,9 +174,12 @@ async fn callback(
 }
 
 mod state {
-    use crate::oauth::{
-        ClientCredentials, CodeVerifier, State,
-        client::{OAuthClient, PartialOAuthClient},
+    use crate::{
+        endpoints::Endpoints,
+        oauth::{
+            ClientCredentials, CodeVerifier, State,
+            client::{OAuthClient, PartialOAuthClient},
+        },
     };
     use std::sync::{Arc, Mutex};
     use tokio::sync::oneshot;
@@ -114,8 +200,10 @@ mod state {
     impl ServerState {
         pub fn new(
             creds: ClientCredentials,
+            endpoints: Endpoints,
             state: State,
             verifier: CodeVerifier,
+            redirect_uri: String,
             cancel: CancellationToken,
             tx: oneshot::Sender<OAuthClient>,
         ) -> Self {
@@ -123,7 +211,7 @@ mod state {
                 inner: Arc::new(ServerStateInner {
                     state,
                     cancel,
-                    client: PartialOAuthClient::new(creds, verifier),
+                    client: PartialOAuthClient::new(creds, endpoints, verifier, redirect_uri),
                     tx: Mutex::new(Some(tx)),
                 }),
             }
@@ -0,0 +1,151 @@
+//! Domain-wide delegation: a Workspace service account impersonates users
+//! of its domain, obtaining tokens with a signed JWT assertion instead of
+//! going through the consent screen.
+
+use super::{AccessToken, Scope, client::REFRESH_MARGIN};
+use crate::{http::GenericClient, metrics::METRICS};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{Method, Url};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::BufReader, path::Path, time::Duration};
+
+const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+/// Lifetime of the assertion, which is also the maximum Google accepts.
+const ASSERTION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// The relevant fields of the JSON key downloaded from the Cloud console.
+#[derive(Deserialize)]
+pub struct ServiceAccountKey {
+    client_email: String,
+    private_key_id: String,
+    private_key: String,
+    token_uri: String,
+}
+
+impl ServiceAccountKey {
+    pub fn load_from_file(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+}
+
+pub struct ServiceAccountClient {
+    key: ServiceAccountKey,
+    encoding_key: EncodingKey,
+    subject: String,
+    scope: Scope,
+    http_client: GenericClient<ErrorResponse>,
+    access_token: AccessToken,
+    expires_at: DateTime<Utc>,
+}
+
+impl ServiceAccountClient {
+    /// Fetches a first access token for `subject`, so that a
+    /// misconfigured delegation is reported right away.
+    pub async fn new(key: ServiceAccountKey, subject: String, scope: Scope) -> eyre::Result<Self> {
+        let encoding_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes())?;
+        // the key carries its token endpoint, which also lets tests point it
+        // to a local server
+        let http_client = GenericClient::builder(Url::parse(&key.token_uri)?).build();
+        let (access_token, expires_at) =
+            fetch_token(&http_client, &key, &encoding_key, &subject, scope).await?;
+        Ok(Self {
+            key,
+            encoding_key,
+            subject,
+            scope,
+            http_client,
+            access_token,
+            expires_at,
+        })
+    }
+
+    pub fn http_client<E>(&self) -> GenericClient<E> {
+        self.http_client.coerce_error()
+    }
+
+    pub fn access_token(&self) -> &AccessToken {
+        &self.access_token
+    }
+
+    pub async fn check_access_token(&mut self) -> eyre::Result<()> {
+        if self.expires_at - REFRESH_MARGIN > Utc::now() {
+            return Ok(());
+        }
+        tracing::info!(subject = %self.subject, "access token about to expire, fetching new one");
+        self.refresh_access_token().await
+    }
+
+    /// There's no refresh token: a new assertion is signed instead.
+    pub async fn refresh_access_token(&mut self) -> eyre::Result<()> {
+        (self.access_token, self.expires_at) = fetch_token(
+            &self.http_client,
+            &self.key,
+            &self.encoding_key,
+            &self.subject,
+            self.scope,
+        )
+        .await?;
+        METRICS.token_refreshes.inc();
+        Ok(())
+    }
+}
+
+async fn fetch_token(
+    http_client: &GenericClient<ErrorResponse>,
+    key: &ServiceAccountKey,
+    encoding_key: &EncodingKey,
+    subject: &str,
+    scope: Scope,
+) -> eyre::Result<(AccessToken, DateTime<Utc>)> {
+    #[derive(Serialize)]
+    struct Claims<'a> {
+        iss: &'a str,
+        sub: &'a str,
+        scope: &'a str,
+        aud: &'a str,
+        iat: i64,
+        exp: i64,
+    }
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: AccessToken,
+        expires_in: u64,
+    }
+
+    let now = Utc::now();
+    let claims = Claims {
+        iss: &key.client_email,
+        sub: subject,
+        scope: scope.as_str(),
+        aud: &key.token_uri,
+        iat: now.timestamp(),
+        exp: (now + ASSERTION_LIFETIME).timestamp(),
+    };
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(key.private_key_id.clone());
+    let assertion = jsonwebtoken::encode(&header, &claims, encoding_key)?;
+
+    let TokenResponse {
+        access_token,
+        expires_in,
+    } = http_client
+        .request([])
+        .method(Method::POST)
+        .form(&[("grant_type", GRANT_TYPE), ("assertion", &assertion)])
+        .send()
+        .await?;
+    let expires_at = now + Duration::from_secs(expires_in);
+    tracing::debug!(subject, %expires_at, "service account token obtained");
+    Ok((access_token, expires_at))
+}
@@ -0,0 +1,199 @@
+//! Progress of a sync: messages processed against the mailbox total, bytes
+//! downloaded, request rate, retries and ETA.
+
+use crate::{format::format_size, metrics::METRICS};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// How often the display is refreshed, or an event emitted.
+const REPORT_INTERVAL: Duration = Duration::from_millis(500);
+const JSON_REPORT_INTERVAL: Duration = Duration::from_secs(2);
+/// Without a terminal or JSON, a log line every this many messages.
+const LOG_EVERY: usize = 1000;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ProgressFormat {
+    /// A live status line on a terminal, periodic log lines otherwise
+    Auto,
+    /// One JSON event per line on stdout, for wrapper scripts
+    Json,
+}
+
+enum Output {
+    Terminal,
+    Json,
+    Log,
+}
+
+pub struct Progress {
+    output: Output,
+    total: Option<usize>,
+    processed: usize,
+    started: Instant,
+    last_report: Instant,
+    last_requests: u64,
+    /// Counters of the process when this run started, as watch mode runs
+    /// several syncs in one process and each reports its own.
+    base_requests: u64,
+    base_bytes: u64,
+    base_retries: u64,
+    /// Requests per second since the previous report.
+    rate: f64,
+}
+
+#[derive(Serialize)]
+struct Event {
+    event: &'static str,
+    processed: usize,
+    total: Option<usize>,
+    bytes: u64,
+    requests: u64,
+    retries: u64,
+    requests_per_sec: f64,
+    eta_secs: Option<u64>,
+    elapsed_secs: u64,
+}
+
+impl Progress {
+    /// `total` is the mailbox size reported by Gmail, which is an estimate:
+    /// the processed count can end up a bit off. Without one, only counts
+    /// are reported.
+    pub fn new(format: ProgressFormat, total: Option<usize>) -> Self {
+        let output = match format {
+            ProgressFormat::Json => Output::Json,
+            ProgressFormat::Auto if std::io::stderr().is_terminal() => Output::Terminal,
+            ProgressFormat::Auto => Output::Log,
+        };
+        let now = Instant::now();
+        let requests = METRICS.requests_total();
+        let progress = Self {
+            output,
+            total,
+            processed: 0,
+            started: now,
+            last_report: now,
+            last_requests: requests,
+            base_requests: requests,
+            base_bytes: METRICS.bytes.get(),
+            base_retries: METRICS.retries.get(),
+            rate: 0.0,
+        };
+        progress.report("start");
+        progress
+    }
+
+    pub fn message_processed(&mut self) {
+        self.processed += 1;
+        match self.output {
+            Output::Log => {
+                if self.processed.is_multiple_of(LOG_EVERY) {
+                    tracing::info!(
+                        total = ?self.total,
+                        "processed {}K messages",
+                        self.processed / LOG_EVERY
+                    );
+                }
+            }
+            Output::Terminal | Output::Json => {
+                let interval = match self.output {
+                    Output::Json => JSON_REPORT_INTERVAL,
+                    _ => REPORT_INTERVAL,
+                };
+                if self.last_report.elapsed() >= interval {
+                    self.update_rate();
+                    self.report("progress");
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.update_rate();
+        self.report("finish");
+        match self.output {
+            Output::Terminal => eprintln!(),
+            Output::Json => (),
+            Output::Log => tracing::info!("processed {} messages", self.processed),
+        }
+    }
+
+    fn update_rate(&mut self) {
+        let requests = METRICS.requests_total();
+        let elapsed = self.last_report.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.rate = (requests - self.last_requests) as f64 / elapsed;
+        }
+        self.last_requests = requests;
+        self.last_report = Instant::now();
+    }
+
+    /// Estimated from the average pace so far, which includes messages that
+    /// were already stored and are quick to skip.
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.checked_sub(self.processed)?;
+        if self.processed == 0 {
+            return None;
+        }
+        let per_message = self.started.elapsed().as_secs_f64() / self.processed as f64;
+        Some(Duration::from_secs_f64(per_message * remaining as f64))
+    }
+
+    fn report(&self, event: &'static str) {
+        let bytes = METRICS.bytes.get() - self.base_bytes;
+        let retries = METRICS.retries.get() - self.base_retries;
+        match self.output {
+            Output::Log => (),
+            Output::Json => {
+                let event = Event {
+                    event,
+                    processed: self.processed,
+                    total: self.total,
+                    bytes,
+                    requests: METRICS.requests_total() - self.base_requests,
+                    retries,
+                    requests_per_sec: (self.rate * 10.0).round() / 10.0,
+                    eta_secs: self.eta().map(|eta| eta.as_secs()),
+                    elapsed_secs: self.started.elapsed().as_secs(),
+                };
+                let mut stdout = std::io::stdout().lock();
+                // a wrapper that stopped reading shouldn't fail the sync
+                let _ = serde_json::to_writer(&mut stdout, &event);
+                let _ = writeln!(stdout);
+            }
+            Output::Terminal => {
+                let messages = match self.total {
+                    Some(total) => {
+                        let percent = match total {
+                            0 => 100.0,
+                            total => self.processed as f64 * 100.0 / total as f64,
+                        };
+                        format!("{}/{total} messages ({percent:.1}%)", self.processed)
+                    }
+                    None => format!("{} messages", self.processed),
+                };
+                let eta = match self.eta() {
+                    Some(eta) => format_duration(eta),
+                    None => "?".to_owned(),
+                };
+                eprint!(
+                    "\r\x1b[2K{messages}  {}  {:.1} req/s  {retries} retries  ETA {eta}",
+                    format_size(bytes as usize),
+                    self.rate,
+                );
+            }
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, _) => format!("{h}h{m:02}m"),
+    }
+}
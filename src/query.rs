@@ -0,0 +1,578 @@
+//! Gmail search syntax, compiled to SQL over the [`Store`](crate::store::Store)
+//! tables.
+//!
+//! Supported operators are `from:`, `to:`, `cc:`, `bcc:`, `subject:`,
+//! `rfc822msgid:`, `label:`, `in:`, `is:`, `category:`, `has:attachment`, `filename:`,
+//! `larger:`, `smaller:`, `before:`, `after:`, `older_than:` and
+//! `newer_than:`, plus bare words, quoted phrases, `OR`, `{...}` groups,
+//! parentheses and `-` negation. Anything else is treated as plain text, as
+//! Gmail does.
+
+use crate::{headers::AddressField, search};
+use chrono::{DateTime, Months, NaiveDate, TimeDelta, Utc};
+use std::{
+    fmt::{self, Write as _},
+    iter::Peekable,
+    str::Chars,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Term {
+    /// A bare word, matched against the full-text index.
+    Text(String),
+    /// A quoted phrase. The index has no positional information, so this
+    /// matches messages containing all of the words in any order.
+    Phrase(String),
+    Address(AddressField, String),
+    Subject(String),
+    /// The `Message-ID` header.
+    InternetMessageId(String),
+    Label(String),
+    HasAttachment,
+    Filename(String),
+    Larger(u64),
+    Smaller(u64),
+    Before(DateTime<Utc>),
+    After(DateTime<Utc>),
+}
+
+/// A query compiled to a boolean SQL expression over `messages m`.
+pub struct CompiledQuery {
+    pub condition: String,
+    pub params: Vec<String>,
+    /// Words that should contribute to relevance ranking.
+    pub ranking_terms: Vec<String>,
+}
+
+pub fn parse(input: &str) -> eyre::Result<Expr> {
+    let tokens = lex(input)?;
+    let mut parser = Parser {
+        tokens: tokens.into_iter().peekable(),
+        now: Utc::now(),
+    };
+    let expr = parser.parse_and(None)?;
+    if let Some(token) = parser.tokens.next() {
+        eyre::bail!("unexpected {token} in query");
+    }
+    Ok(expr)
+}
+
+pub fn compile(expr: &Expr) -> CompiledQuery {
+    let mut compiled = CompiledQuery {
+        condition: String::new(),
+        params: Vec::new(),
+        ranking_terms: Vec::new(),
+    };
+    compile_expr(expr, false, &mut compiled);
+    compiled.ranking_terms.sort();
+    compiled.ranking_terms.dedup();
+    compiled
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    /// An `operator:value` pair.
+    Operator(String, String),
+    Minus,
+    Or,
+    OpenParen,
+    CloseParen,
+    OpenBrace,
+    CloseBrace,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::Phrase(phrase) => write!(f, "'\"{phrase}\"'"),
+            Token::Operator(op, value) => write!(f, "'{op}:{value}'"),
+            Token::Minus => f.write_str("'-'"),
+            Token::Or => f.write_str("'OR'"),
+            Token::OpenParen => f.write_str("'('"),
+            Token::CloseParen => f.write_str("')'"),
+            Token::OpenBrace => f.write_str("'{'"),
+            Token::CloseBrace => f.write_str("'}'"),
+        }
+    }
+}
+
+fn lex(input: &str) -> eyre::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' | '{' | '}' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::OpenParen,
+                    ')' => Token::CloseParen,
+                    '{' => Token::OpenBrace,
+                    _ => Token::CloseBrace,
+                });
+            }
+            '-' => {
+                chars.next();
+                tokens.push(Token::Minus);
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Phrase(lex_quoted(&mut chars)?));
+            }
+            _ => {
+                let word = lex_word(&mut chars);
+                if word == "OR" || word == "|" {
+                    tokens.push(Token::Or);
+                } else if let Some((op, value)) = word.split_once(':') {
+                    let value = if value.is_empty() && chars.peek() == Some(&'"') {
+                        chars.next();
+                        lex_quoted(&mut chars)?
+                    } else {
+                        value.to_owned()
+                    };
+                    tokens.push(Token::Operator(op.to_ascii_lowercase(), value));
+                } else {
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn lex_word(chars: &mut Peekable<Chars>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || matches!(c, '(' | ')' | '{' | '}' | '"') {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+fn lex_quoted(chars: &mut Peekable<Chars>) -> eyre::Result<String> {
+    let mut phrase = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(phrase);
+        }
+        phrase.push(c);
+    }
+    eyre::bail!("unterminated quote in query")
+}
+
+struct Parser<I: Iterator<Item = Token>> {
+    tokens: Peekable<I>,
+    now: DateTime<Utc>,
+}
+
+impl<I: Iterator<Item = Token>> Parser<I> {
+    /// Parses a sequence of implicitly AND-ed expressions, up to `close` or
+    /// the end of the input.
+    fn parse_and(&mut self, close: Option<Token>) -> eyre::Result<Expr> {
+        let mut exprs = Vec::new();
+        loop {
+            match self.tokens.peek() {
+                None if close.is_none() => break,
+                None => eyre::bail!("unbalanced parentheses in query"),
+                Some(token) if Some(token) == close.as_ref() => {
+                    self.tokens.next();
+                    break;
+                }
+                Some(_) => exprs.push(self.parse_or()?),
+            }
+        }
+        Ok(flatten(exprs, Expr::And))
+    }
+
+    fn parse_or(&mut self) -> eyre::Result<Expr> {
+        let mut exprs = Vec::from([self.parse_unary()?]);
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            exprs.push(self.parse_unary()?);
+        }
+        Ok(flatten(exprs, Expr::Or))
+    }
+
+    fn parse_unary(&mut self) -> eyre::Result<Expr> {
+        match self.tokens.next() {
+            Some(Token::Minus) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::OpenParen) => self.parse_and(Some(Token::CloseParen)),
+            Some(Token::OpenBrace) => {
+                let mut exprs = Vec::new();
+                while self.tokens.next_if_eq(&Token::CloseBrace).is_none() {
+                    if self.tokens.peek().is_none() {
+                        eyre::bail!("unbalanced braces in query");
+                    }
+                    // an OR inside braces is redundant but harmless
+                    self.tokens.next_if_eq(&Token::Or);
+                    exprs.push(self.parse_unary()?);
+                }
+                Ok(flatten(exprs, Expr::Or))
+            }
+            Some(Token::Word(word)) => Ok(Expr::Term(Term::Text(word))),
+            Some(Token::Phrase(phrase)) => Ok(Expr::Term(Term::Phrase(phrase))),
+            Some(Token::Operator(op, value)) => self.parse_operator(op, value),
+            Some(token) => eyre::bail!("unexpected {token} in query"),
+            None => eyre::bail!("unexpected end of query"),
+        }
+    }
+
+    fn parse_operator(&self, op: String, value: String) -> eyre::Result<Expr> {
+        let term = match op.as_str() {
+            "from" => Term::Address(AddressField::From, value),
+            "to" => Term::Address(AddressField::To, value),
+            "cc" => Term::Address(AddressField::Cc, value),
+            "bcc" => Term::Address(AddressField::Bcc, value),
+            "subject" => Term::Subject(value),
+            "rfc822msgid" => Term::InternetMessageId(
+                value
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_owned(),
+            ),
+            "label" | "in" => Term::Label(value),
+            "category" => Term::Label(format!("CATEGORY_{value}")),
+            "is" => return parse_is(&value),
+            "has" if value.eq_ignore_ascii_case("attachment") => Term::HasAttachment,
+            "filename" => Term::Filename(value),
+            "larger" | "size" => Term::Larger(parse_size(&value)?),
+            "smaller" => Term::Smaller(parse_size(&value)?),
+            "before" => Term::Before(parse_date(&value)?),
+            "after" => Term::After(parse_date(&value)?),
+            "older_than" => Term::Before(self.parse_relative(&value)?),
+            "newer_than" => Term::After(self.parse_relative(&value)?),
+            _ => Term::Text(format!("{op}:{value}")),
+        };
+        Ok(Expr::Term(term))
+    }
+
+    fn parse_relative(&self, value: &str) -> eyre::Result<DateTime<Utc>> {
+        relative_date(self.now, value).ok_or_else(|| {
+            eyre::eyre!("invalid relative date {value:?}, expected e.g. 2d, 3m or 1y")
+        })
+    }
+}
+
+fn parse_is(value: &str) -> eyre::Result<Expr> {
+    let label = |id: &str| Expr::Term(Term::Label(id.to_owned()));
+    let expr = match value.to_ascii_lowercase().as_str() {
+        "unread" => label("UNREAD"),
+        "read" => Expr::Not(Box::new(label("UNREAD"))),
+        "starred" => label("STARRED"),
+        "important" => label("IMPORTANT"),
+        "sent" => label("SENT"),
+        "draft" => label("DRAFT"),
+        "chat" => label("CHAT"),
+        other => eyre::bail!("unsupported is:{other}"),
+    };
+    Ok(expr)
+}
+
+/// Resolves a relative date such as `2d`, `3m` or `1y` against `now`.
+pub fn relative_date(now: DateTime<Utc>, value: &str) -> Option<DateTime<Utc>> {
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u32 = amount.parse().ok()?;
+    match unit.to_ascii_lowercase().as_str() {
+        "d" => now.checked_sub_signed(TimeDelta::days(amount.into())),
+        "m" => now.checked_sub_months(Months::new(amount)),
+        "y" => now.checked_sub_months(Months::new(amount.checked_mul(12)?)),
+        _ => None,
+    }
+}
+
+/// Parses sizes like `1024`, `512K` or `10M`.
+pub fn parse_size(value: &str) -> eyre::Result<u64> {
+    let (digits, multiplier) = match value.to_ascii_uppercase() {
+        v if v.ends_with('K') => (v.trim_end_matches('K').to_owned(), 1 << 10),
+        v if v.ends_with('M') => (v.trim_end_matches('M').to_owned(), 1 << 20),
+        v if v.ends_with('G') => (v.trim_end_matches('G').to_owned(), 1 << 30),
+        v => (v, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| eyre::eyre!("invalid size {value:?}, expected e.g. 512K or 10M"))
+}
+
+fn parse_date(value: &str) -> eyre::Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(value, "%Y/%m/%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d"))
+        .map_err(|_| eyre::eyre!("invalid date {value:?}, expected YYYY/MM/DD"))?;
+    Ok(date.and_time(Default::default()).and_utc())
+}
+
+fn flatten(mut exprs: Vec<Expr>, make: impl FnOnce(Vec<Expr>) -> Expr) -> Expr {
+    if exprs.len() == 1 {
+        exprs.pop().unwrap()
+    } else {
+        make(exprs)
+    }
+}
+
+fn compile_expr(expr: &Expr, negated: bool, out: &mut CompiledQuery) {
+    match expr {
+        Expr::And(exprs) | Expr::Or(exprs) if exprs.is_empty() => out.condition.push_str("TRUE"),
+        Expr::And(exprs) | Expr::Or(exprs) => {
+            let sep = if matches!(expr, Expr::And(_)) {
+                " AND "
+            } else {
+                " OR "
+            };
+            out.condition.push('(');
+            for (i, expr) in exprs.iter().enumerate() {
+                if i > 0 {
+                    out.condition.push_str(sep);
+                }
+                compile_expr(expr, negated, out);
+            }
+            out.condition.push(')');
+        }
+        Expr::Not(expr) => {
+            out.condition.push_str("NOT ");
+            compile_expr(expr, !negated, out);
+        }
+        Expr::Term(term) => compile_term(term, negated, out),
+    }
+}
+
+fn compile_term(term: &Term, negated: bool, out: &mut CompiledQuery) {
+    let cond = &mut out.condition;
+    let params = &mut out.params;
+    match term {
+        Term::Text(text) | Term::Phrase(text) => {
+            let terms: Vec<_> = search::tokenize(text).collect();
+            if terms.is_empty() {
+                cond.push_str("TRUE");
+                return;
+            }
+            cond.push('(');
+            for (i, term) in terms.iter().enumerate() {
+                if i > 0 {
+                    cond.push_str(" AND ");
+                }
+                cond.push_str(
+                    "EXISTS (SELECT 1 FROM search_terms t
+                    WHERE t.message_id = m.id AND t.term = ?)",
+                );
+                params.push(term.clone());
+            }
+            cond.push(')');
+            if !negated {
+                out.ranking_terms.extend(terms);
+            }
+        }
+        Term::Address(field, value) => {
+            cond.push_str(
+                "EXISTS (SELECT 1 FROM message_addresses a
+                WHERE a.message_id = m.id AND a.field = ? AND (
+                    a.address ILIKE ? ESCAPE '\\' OR a.display_name ILIKE ? ESCAPE '\\'
+                ))",
+            );
+            params.push(field.as_str().to_owned());
+            params.push(like_pattern(value));
+            params.push(like_pattern(value));
+        }
+        Term::Subject(value) => {
+            cond.push_str(
+                "EXISTS (SELECT 1 FROM message_headers h
+                WHERE h.message_id = m.id AND h.subject ILIKE ? ESCAPE '\\')",
+            );
+            params.push(like_pattern(value));
+        }
+        Term::InternetMessageId(id) => {
+            cond.push_str(
+                "EXISTS (SELECT 1 FROM message_headers h
+                WHERE h.message_id = m.id AND h.internet_message_id = ?)",
+            );
+            params.push(id.clone());
+        }
+        Term::Label(label) => {
+            // Gmail shows nested labels and spaces as dashes in the search box
+            cond.push_str(
+                "EXISTS (SELECT 1 FROM message_labels ml
+                JOIN labels l ON l.id = ml.label_id
+                WHERE ml.message_id = m.id AND (
+                    lower(l.id) = lower(?)
+                    OR lower(regexp_replace(l.name, '[ /]', '-', 'g')) = lower(?)
+                ))",
+            );
+            let label = label.replace([' ', '/'], "-");
+            params.push(label.clone());
+            params.push(label);
+        }
+        Term::HasAttachment => cond.push_str(
+            "EXISTS (SELECT 1 FROM message_part_body b
+            WHERE b.message_id = m.id AND b.attachment_id IS NOT NULL)",
+        ),
+        Term::Filename(name) => {
+            cond.push_str(
+                "EXISTS (SELECT 1 FROM message_parts p
+                WHERE p.message_id = m.id AND p.filename ILIKE ? ESCAPE '\\')",
+            );
+            params.push(like_pattern(name));
+        }
+        Term::Larger(size) => {
+            let _ = write!(cond, "m.size_estimate > {size}");
+        }
+        Term::Smaller(size) => {
+            let _ = write!(cond, "m.size_estimate < {size}");
+        }
+        Term::Before(date) => {
+            cond.push_str("m.internal_date < ?::TIMESTAMP");
+            params.push(date.to_rfc3339());
+        }
+        Term::After(date) => {
+            cond.push_str("m.internal_date >= ?::TIMESTAMP");
+            params.push(date.to_rfc3339());
+        }
+    }
+}
+
+fn like_pattern(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn term(term: Term) -> Expr {
+        Expr::Term(term)
+    }
+
+    fn text(word: &str) -> Expr {
+        term(Term::Text(word.to_owned()))
+    }
+
+    fn from(address: &str) -> Expr {
+        term(Term::Address(AddressField::From, address.to_owned()))
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("10m").unwrap(), 10 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("99999999999G").is_err());
+        assert!(parse_size("ten").is_err());
+        assert!(parse_size("-1").is_err());
+        assert_eq!(parse("larger:1K").unwrap(), term(Term::Larger(1024)));
+        assert!(parse("larger:99999999999G").is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        let date = Utc.with_ymd_and_hms(2020, 1, 2, 0, 0, 0).unwrap();
+        assert_eq!(
+            parse("before:2020/01/02").unwrap(),
+            term(Term::Before(date))
+        );
+        assert_eq!(parse("after:2020-01-02").unwrap(), term(Term::After(date)));
+        assert!(parse("after:2020/13/01").is_err());
+
+        let now = Utc.with_ymd_and_hms(2025, 3, 31, 12, 0, 0).unwrap();
+        assert_eq!(
+            relative_date(now, "2d"),
+            Some(Utc.with_ymd_and_hms(2025, 3, 29, 12, 0, 0).unwrap())
+        );
+        // clamped to the end of a shorter month
+        assert_eq!(
+            relative_date(now, "1m"),
+            Some(Utc.with_ymd_and_hms(2025, 2, 28, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            relative_date(now, "1y"),
+            Some(Utc.with_ymd_and_hms(2024, 3, 31, 12, 0, 0).unwrap())
+        );
+        assert_eq!(relative_date(now, "3w"), None);
+        assert!(parse("older_than:soon").is_err());
+    }
+
+    #[test]
+    fn parses_or() {
+        assert_eq!(
+            parse("from:alice OR from:bob").unwrap(),
+            Expr::Or(vec![from("alice"), from("bob")])
+        );
+        assert_eq!(
+            parse("{from:alice from:bob} invoice").unwrap(),
+            Expr::And(vec![
+                Expr::Or(vec![from("alice"), from("bob")]),
+                text("invoice")
+            ])
+        );
+        // OR binds tighter than the implicit AND
+        assert_eq!(
+            parse("a b OR c").unwrap(),
+            Expr::And(vec![text("a"), Expr::Or(vec![text("b"), text("c")])])
+        );
+        assert_eq!(
+            parse("(a b) OR c").unwrap(),
+            Expr::Or(vec![Expr::And(vec![text("a"), text("b")]), text("c")])
+        );
+        assert!(parse("(a b").is_err());
+        assert!(parse("{a b").is_err());
+    }
+
+    #[test]
+    fn parses_negation() {
+        assert_eq!(
+            parse("-label:Travel").unwrap(),
+            Expr::Not(Box::new(term(Term::Label("Travel".to_owned()))))
+        );
+        assert_eq!(
+            parse("-(a b)").unwrap(),
+            Expr::Not(Box::new(Expr::And(vec![text("a"), text("b")])))
+        );
+        assert_eq!(
+            parse("is:read").unwrap(),
+            Expr::Not(Box::new(term(Term::Label("UNREAD".to_owned()))))
+        );
+    }
+
+    #[test]
+    fn parses_phrases() {
+        assert_eq!(
+            parse("\"quarterly report\" has:attachment").unwrap(),
+            Expr::And(vec![
+                term(Term::Phrase("quarterly report".to_owned())),
+                term(Term::HasAttachment),
+            ])
+        );
+        assert_eq!(
+            parse("subject:\"hello world\"").unwrap(),
+            term(Term::Subject("hello world".to_owned()))
+        );
+        assert!(parse("\"unterminated").is_err());
+    }
+
+    #[test]
+    fn binds_values_as_parameters() {
+        let compiled = compile(&parse("from:\"alice' OR 1=1\"").unwrap());
+        assert!(!compiled.condition.contains("alice'"));
+        assert!(compiled.params.iter().any(|param| param.contains("alice'")));
+    }
+}
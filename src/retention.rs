@@ -0,0 +1,242 @@
+//! Retention rules, removing old mail from Gmail once it's archived.
+//!
+//! Rules are Gmail search queries, read from a TOML file and evaluated
+//! against the store rather than Gmail, so only what the archive knows about
+//! can match. Messages that aren't completely stored are never removed. A
+//! message matching several rules is removed by the first one.
+//!
+//! ```toml
+//! [[rule]]
+//! name = "old promotions"
+//! query = "category:promotions older_than:1y"
+//!
+//! [[rule]]
+//! name = "large old mail"
+//! query = "larger:10M older_than:3y"
+//! action = "delete"
+//! ```
+
+use crate::{
+    client::{BATCH_DELETE_LIMIT, GmailClient},
+    format::format_size,
+    http::{FailureKind, error::DefaultError, is_failure},
+    model::MessageId,
+    oauth::Scope,
+    query,
+    shutdown::Interrupted,
+    store::Store,
+};
+use chrono::Utc;
+use clap::Subcommand;
+use eyre::WrapErr;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Subcommand)]
+pub enum RetentionCommand {
+    /// Show what the rules would remove from Gmail and the space it would
+    /// free, without contacting Gmail
+    Plan {
+        /// TOML file of `[[rule]]` tables with a `name`, a `query` and an
+        /// optional `action`, `trash` (default) or `delete`
+        rules: PathBuf,
+    },
+    /// Remove from Gmail the archived messages matching the rules
+    Apply {
+        /// Same as for `plan`
+        rules: PathBuf,
+        /// Confirm permanently deleting the messages of `delete` rules,
+        /// which can't be undone
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(rename = "rule", default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    name: String,
+    query: String,
+    #[serde(default)]
+    action: Action,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    /// Gmail deletes trashed messages after 30 days.
+    #[default]
+    Trash,
+    /// Permanently, right away.
+    Delete,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Trash => "trash",
+            Action::Delete => "delete",
+        }
+    }
+
+    fn scope(&self) -> Scope {
+        match self {
+            Action::Trash => Scope::Modify,
+            Action::Delete => Scope::Full,
+        }
+    }
+}
+
+pub fn load_rules(path: &Path) -> eyre::Result<Vec<Rule>> {
+    let contents = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read retention rules {}", path.display()))?;
+    let file: RulesFile = toml::from_str(&contents)
+        .wrap_err_with(|| format!("invalid retention rules {}", path.display()))?;
+    Ok(file.rules)
+}
+
+pub struct Plan {
+    rules: Vec<RulePlan>,
+}
+
+struct RulePlan {
+    rule: Rule,
+    /// Fully archived, so removable.
+    ids: Vec<MessageId>,
+    bytes: usize,
+    /// Matching, but kept until they're completely stored.
+    not_archived: usize,
+}
+
+impl Plan {
+    /// The access that applying the plan needs, if there's anything to do.
+    pub fn scope(&self) -> Option<Scope> {
+        self.rules
+            .iter()
+            .filter(|plan| !plan.ids.is_empty())
+            .map(|plan| plan.rule.action.scope())
+            .max()
+    }
+
+    /// How many messages would be deleted rather than trashed.
+    pub fn permanent_deletions(&self) -> usize {
+        self.rules
+            .iter()
+            .filter(|plan| matches!(plan.rule.action, Action::Delete))
+            .map(|plan| plan.ids.len())
+            .sum()
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{:<30} {:<7} {:>9} {:>11} {:>13}",
+            "rule", "action", "messages", "size", "not archived"
+        );
+        for plan in &self.rules {
+            println!(
+                "{:<30} {:<7} {:>9} {:>11} {:>13}",
+                plan.rule.name,
+                plan.rule.action.as_str(),
+                plan.ids.len(),
+                format_size(plan.bytes),
+                plan.not_archived,
+            );
+        }
+        println!(
+            "{:<38} {:>9} {:>11} {:>13}",
+            "total",
+            self.rules.iter().map(|plan| plan.ids.len()).sum::<usize>(),
+            format_size(self.rules.iter().map(|plan| plan.bytes).sum()),
+            self.rules
+                .iter()
+                .map(|plan| plan.not_archived)
+                .sum::<usize>(),
+        );
+    }
+}
+
+pub fn plan(store: &Store, rules: Vec<Rule>) -> eyre::Result<Plan> {
+    let mut claimed = HashSet::new();
+    let mut plans = Vec::new();
+    for rule in rules {
+        let query = query::parse(&rule.query)
+            .wrap_err_with(|| format!("invalid query for rule {:?}", rule.name))?;
+        let mut plan = RulePlan {
+            rule,
+            ids: Vec::new(),
+            bytes: 0,
+            not_archived: 0,
+        };
+        for candidate in store.retention_candidates(&query::compile(&query))? {
+            if !claimed.insert(candidate.id.to_string()) {
+                continue;
+            }
+            if candidate.complete {
+                plan.bytes += candidate.size_estimate;
+                plan.ids.push(candidate.id);
+            } else {
+                plan.not_archived += 1;
+            }
+        }
+        plans.push(plan);
+    }
+    Ok(Plan { rules: plans })
+}
+
+/// Removes the planned messages from Gmail, recording each removal so that
+/// later plans leave them out. Stops between messages once `cancel` is
+/// cancelled.
+pub async fn apply(
+    client: &GmailClient,
+    store: &Store,
+    plan: &Plan,
+    cancel: &CancellationToken,
+) -> eyre::Result<()> {
+    for RulePlan { rule, ids, .. } in &plan.rules {
+        if ids.is_empty() {
+            continue;
+        }
+        tracing::info!(rule = rule.name, "removing {} messages", ids.len());
+        let action = rule.action.as_str();
+        match rule.action {
+            Action::Trash => {
+                for id in ids {
+                    if cancel.is_cancelled() {
+                        return Err(Interrupted.into());
+                    }
+                    match client.trash_message(id).await {
+                        Ok(()) => (),
+                        Err(err) if is_failure::<DefaultError>(&err, FailureKind::NotFound) => {
+                            tracing::debug!(%id, "message already gone from Gmail");
+                        }
+                        Err(err) => return Err(err),
+                    }
+                    store.record_remote_removal(id, &rule.name, action, Utc::now())?;
+                }
+            }
+            Action::Delete => {
+                for batch in ids.chunks(BATCH_DELETE_LIMIT) {
+                    if cancel.is_cancelled() {
+                        return Err(Interrupted.into());
+                    }
+                    client.batch_delete_messages(batch).await?;
+                    for id in batch {
+                        store.record_remote_removal(id, &rule.name, action, Utc::now())?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use crate::{
+    mime,
+    model::{FullMessage, Header, MessagePart},
+};
+use std::collections::HashMap;
+
+/// Headers that are indexed alongside the message text.
+const INDEXED_HEADERS: [(&str, Field); 3] = [
+    ("subject", Field::Subject),
+    ("from", Field::From),
+    ("to", Field::To),
+];
+
+const MIN_TERM_LEN: usize = 2;
+const MAX_TERM_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    Subject,
+    From,
+    To,
+    Body,
+}
+
+impl Field {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Field::Subject => "subject",
+            Field::From => "from",
+            Field::To => "to",
+            Field::Body => "body",
+        }
+    }
+}
+
+/// A part of a message as seen by the indexer. This is decoupled from
+/// [`MessagePart`] so that the index can also be rebuilt from the store.
+pub struct IndexablePart<'a> {
+    pub mime_type: &'a str,
+    pub charset: Option<String>,
+    pub data: Option<&'a [u8]>,
+}
+
+impl<'a> From<&'a MessagePart> for IndexablePart<'a> {
+    fn from(part: &'a MessagePart) -> Self {
+        Self {
+            mime_type: &part.mime_type,
+            charset: mime::content_type(&part.headers)
+                .param("charset")
+                .map(str::to_owned),
+            data: part.body.data.as_deref(),
+        }
+    }
+}
+
+/// Term frequencies for a single message, keyed by term and field.
+pub struct Document {
+    pub terms: HashMap<(String, Field), usize>,
+    pub length: usize,
+}
+
+impl Document {
+    pub fn from_message(message: &FullMessage) -> Self {
+        let mut parts = Vec::from([&message.payload]);
+        let mut indexable = Vec::new();
+        while let Some(part) = parts.pop() {
+            indexable.push(IndexablePart::from(part));
+            parts.extend(part.parts.iter());
+        }
+        Self::from_parts(&message.payload.headers, indexable)
+    }
+
+    pub fn from_parts<'a>(
+        root_headers: &[Header],
+        parts: impl IntoIterator<Item = IndexablePart<'a>>,
+    ) -> Self {
+        let mut doc = Self {
+            terms: HashMap::new(),
+            length: 0,
+        };
+        for header in root_headers {
+            let name = header.name.to_ascii_lowercase();
+            if let Some((_, field)) = INDEXED_HEADERS.iter().find(|(n, _)| *n == name) {
+                doc.add_text(&mime::decode_header(&header.value), *field);
+            }
+        }
+        for part in parts {
+            let Some(data) = part.data else {
+                continue;
+            };
+            let text = mime::decode_charset(data, part.charset.as_deref());
+            match part.mime_type {
+                "text/plain" => doc.add_text(&text, Field::Body),
+                "text/html" => doc.add_text(&mime::html_to_text(&text), Field::Body),
+                _ => (),
+            }
+        }
+        doc
+    }
+
+    fn add_text(&mut self, text: &str, field: Field) {
+        for term in tokenize(text) {
+            *self.terms.entry((term, field)).or_default() += 1;
+            self.length += 1;
+        }
+    }
+}
+
+/// Splits text into lowercase alphanumeric terms. Queries go through the
+/// same function, so whatever is done here applies to both sides.
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|s| (MIN_TERM_LEN..=MAX_TERM_LEN).contains(&s.chars().count()))
+        .map(str::to_lowercase)
+}
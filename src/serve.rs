@@ -0,0 +1,197 @@
+//! A small local web interface to browse the archive: search, message view
+//! and statistics.
+
+use crate::{
+    format::format_size,
+    model::MessageId,
+    query,
+    show::MessageView,
+    stats::{self, Period},
+    store::Store,
+};
+use axum::{
+    Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use maud::{DOCTYPE, Markup, html};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+const SEARCH_LIMIT: usize = 100;
+const STATS_TOP: usize = 20;
+
+pub async fn serve(store: Store, bind: SocketAddr) -> eyre::Result<()> {
+    let router = Router::new()
+        .route("/", get(search))
+        .route("/messages/{id}", get(message))
+        .route("/stats", get(statistics))
+        .with_state(store);
+    let listener = TcpListener::bind(bind).await?;
+    tracing::info!("serving on http://{}", listener.local_addr()?);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    #[serde(default)]
+    q: String,
+}
+
+async fn search(
+    State(store): State<Store>,
+    Query(params): Query<SearchParams>,
+) -> Result<Html<String>, ServerError> {
+    let hits = if params.q.trim().is_empty() {
+        None
+    } else {
+        let query = query::compile(&query::parse(&params.q).map_err(ServerError::BadQuery)?);
+        Some(store.search(&query, SEARCH_LIMIT)?)
+    };
+    Ok(Html(
+        page(
+            "Search",
+            html! {
+                form action="/" method="get" {
+                    input type="search" name="q" value=(params.q) size="60" autofocus;
+                    " "
+                    button type="submit" { "Search" }
+                }
+                @if let Some(hits) = hits {
+                    @if hits.is_empty() {
+                        p { "No messages found." }
+                    }
+                    table {
+                        @for hit in &hits {
+                            tr {
+                                td.date { (hit.internal_date.format("%Y-%m-%d")) }
+                                td { (hit.from.as_deref().unwrap_or("(unknown sender)")) }
+                                td {
+                                    a href={ "/messages/" (hit.message_id) } {
+                                        (hit.subject.as_deref().unwrap_or("(no subject)"))
+                                    }
+                                    @if let Some(snippet) = hit.snippet.as_deref().filter(|s| !s.is_empty()) {
+                                        div.snippet { (snippet) }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        )
+        .into_string(),
+    ))
+}
+
+async fn message(
+    State(store): State<Store>,
+    Path(id): Path<String>,
+) -> Result<Html<String>, ServerError> {
+    let id = MessageId::from(id);
+    let message = MessageView::load(&store, &id)?.ok_or(ServerError::NotFound)?;
+    Ok(Html(
+        page(
+            message
+                .headers
+                .iter()
+                .find(|(name, _)| *name == "Subject")
+                .map_or("(no subject)", |(_, subject)| subject),
+            html! {
+                table.headers {
+                    tr { th { "Date" } td { (message.date.to_rfc2822()) } }
+                    @for (name, value) in &message.headers {
+                        tr { th { (name) } td { (value) } }
+                    }
+                    @if !message.labels.is_empty() {
+                        tr { th { "Labels" } td { (message.labels.join(", ")) } }
+                    }
+                }
+                pre { (message.body.as_deref().unwrap_or("(no text body)")) }
+                @if !message.attachments.is_empty() {
+                    h2 { "Attachments" }
+                    ul {
+                        @for attachment in &message.attachments {
+                            li {
+                                (attachment.filename) " (" (attachment.mime_type) ", "
+                                (format_size(attachment.size)) ")"
+                            }
+                        }
+                    }
+                }
+            },
+        )
+        .into_string(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct StatsParams {
+    period: Option<String>,
+}
+
+async fn statistics(
+    State(store): State<Store>,
+    Query(params): Query<StatsParams>,
+) -> Result<Html<String>, ServerError> {
+    let period = match params.period.as_deref() {
+        Some("week") => Period::Week,
+        Some("year") => Period::Year,
+        _ => Period::Month,
+    };
+    let stats = store.stats(period.as_str(), period.format(), STATS_TOP)?;
+    Ok(Html(stats::render_html(&stats, period).into_string()))
+}
+
+fn page(title: &str, content: Markup) -> Markup {
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                title { "Gmail Archiver - " (title) }
+                style {
+                    "body { font-family: sans-serif; margin: 2em; }"
+                    "td, th { padding: 0.2em 0.5em; text-align: left; vertical-align: top; }"
+                    "td.date { white-space: nowrap; }"
+                    ".snippet { color: #666; font-size: 0.9em; }"
+                    "pre { white-space: pre-wrap; }"
+                }
+            }
+            body {
+                nav { a href="/" { "Search" } " | " a href="/stats" { "Statistics" } }
+                (content)
+            }
+        }
+    }
+}
+
+enum ServerError {
+    BadQuery(eyre::Report),
+    NotFound,
+    Generic(eyre::Report),
+}
+
+impl From<eyre::Report> for ServerError {
+    fn from(value: eyre::Report) -> Self {
+        Self::Generic(value)
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        match self {
+            ServerError::BadQuery(report) => {
+                (StatusCode::BAD_REQUEST, format!("{report}")).into_response()
+            }
+            ServerError::NotFound => (StatusCode::NOT_FOUND, "message not found").into_response(),
+            ServerError::Generic(report) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{report:?}")).into_response()
+            }
+        }
+    }
+}
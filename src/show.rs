@@ -0,0 +1,183 @@
+use crate::{
+    format::format_size,
+    mime::{self, Entity},
+    model::{Header, MessageId},
+    store::{Store, StoredPart},
+};
+use chrono::{DateTime, Utc};
+use std::fmt::Write as _;
+
+const DISPLAYED_HEADERS: [&str; 4] = ["From", "To", "Cc", "Subject"];
+
+/// A message part reduced to what the viewer cares about, regardless of
+/// whether it came from the raw message or from the stored parts.
+struct ViewPart {
+    mime_type: String,
+    charset: Option<String>,
+    filename: Option<String>,
+    /// Without transfer encoding, but still in the original charset.
+    data: Option<Vec<u8>>,
+    size: usize,
+}
+
+impl ViewPart {
+    fn is_attachment(&self) -> bool {
+        self.filename.is_some()
+    }
+
+    fn text(&self) -> Option<String> {
+        let data = self.data.as_deref()?;
+        let text = mime::decode_charset(data, self.charset.as_deref());
+        match self.mime_type.as_str() {
+            "text/plain" => Some(text.into_owned()),
+            "text/html" => Some(mime::html_to_text(&text)),
+            _ => None,
+        }
+    }
+}
+
+/// A stored message, decoded for display.
+pub struct MessageView {
+    pub date: DateTime<Utc>,
+    /// Decoded values of the headers worth displaying, in display order.
+    pub headers: Vec<(&'static str, String)>,
+    pub labels: Vec<String>,
+    pub body: Option<String>,
+    pub attachments: Vec<AttachmentView>,
+}
+
+pub struct AttachmentView {
+    pub filename: String,
+    pub mime_type: String,
+    pub size: usize,
+}
+
+impl MessageView {
+    pub fn load(store: &Store, id: &MessageId) -> eyre::Result<Option<Self>> {
+        let Some(message) = store.message_summary(id)? else {
+            return Ok(None);
+        };
+        // the raw message is authoritative, but it's fetched separately and
+        // may not be in the store yet
+        let (headers, parts) = match store.raw_message(id)? {
+            Some(raw) => from_raw(&raw),
+            None => from_stored(store.message_parts(id)?),
+        };
+        let mut displayed = Vec::new();
+        for name in DISPLAYED_HEADERS {
+            for header in headers.iter().filter(|h| h.name.eq_ignore_ascii_case(name)) {
+                displayed.push((name, mime::decode_header(&header.value)));
+            }
+        }
+        Ok(Some(Self {
+            date: message.internal_date,
+            headers: displayed,
+            labels: message.labels,
+            body: best_body(&parts),
+            attachments: parts
+                .into_iter()
+                .filter_map(|part| {
+                    Some(AttachmentView {
+                        filename: part.filename?,
+                        mime_type: part.mime_type,
+                        size: part.size,
+                    })
+                })
+                .collect(),
+        }))
+    }
+}
+
+pub fn show(store: &Store, id: &MessageId) -> eyre::Result<()> {
+    let Some(message) = MessageView::load(store, id)? else {
+        eyre::bail!("message {id} not found in the store");
+    };
+
+    let mut out = String::new();
+    writeln!(out, "Date: {}", message.date.to_rfc2822())?;
+    for (name, value) in &message.headers {
+        writeln!(out, "{name}: {value}")?;
+    }
+    if !message.labels.is_empty() {
+        writeln!(out, "Labels: {}", message.labels.join(", "))?;
+    }
+    writeln!(out)?;
+
+    match &message.body {
+        Some(text) => writeln!(out, "{}", text.trim_end())?,
+        None => writeln!(out, "(no text body)")?,
+    }
+
+    if !message.attachments.is_empty() {
+        writeln!(out, "\nAttachments:")?;
+        for attachment in &message.attachments {
+            writeln!(
+                out,
+                "  {}  {}  {}",
+                attachment.filename,
+                attachment.mime_type,
+                format_size(attachment.size)
+            )?;
+        }
+    }
+    print!("{out}");
+    Ok(())
+}
+
+/// Prefers plain text, as it's what the sender intended for terminals.
+fn best_body(parts: &[ViewPart]) -> Option<String> {
+    let candidates = || parts.iter().filter(|p| !p.is_attachment());
+    candidates()
+        .filter(|p| p.mime_type == "text/plain")
+        .chain(candidates().filter(|p| p.mime_type == "text/html"))
+        .find_map(ViewPart::text)
+}
+
+fn from_raw(raw: &[u8]) -> (Vec<Header>, Vec<ViewPart>) {
+    let root = Entity::parse(raw);
+    let parts = root
+        .walk()
+        .into_iter()
+        .filter(|entity| matches!(entity.body, mime::Body::Single(_)))
+        .map(|entity| {
+            let content_type = entity.content_type();
+            let data = entity.decoded_body();
+            let is_attachment = entity.header("content-disposition").is_some_and(|d| {
+                d.trim_start()
+                    .to_ascii_lowercase()
+                    .starts_with("attachment")
+            });
+            let filename = entity
+                .filename()
+                .or_else(|| is_attachment.then(|| "(unnamed)".to_owned()));
+            ViewPart {
+                charset: content_type.param("charset").map(str::to_owned),
+                mime_type: content_type.mime_type,
+                filename,
+                size: data.as_ref().map_or(0, Vec::len),
+                data,
+            }
+        })
+        .collect();
+    (root.headers, parts)
+}
+
+fn from_stored(parts: Vec<StoredPart>) -> (Vec<Header>, Vec<ViewPart>) {
+    let mut headers = Vec::new();
+    let mut view_parts = Vec::new();
+    for part in parts {
+        if part.part_id.is_empty() {
+            headers = part.headers.clone();
+        }
+        view_parts.push(ViewPart {
+            charset: mime::content_type(&part.headers)
+                .param("charset")
+                .map(str::to_owned),
+            mime_type: part.mime_type,
+            filename: Some(part.filename).filter(|f| !f.is_empty()),
+            size: part.size,
+            data: part.data,
+        });
+    }
+    (headers, view_parts)
+}
@@ -0,0 +1,55 @@
+//! Graceful shutdown on SIGINT and SIGTERM: the first signal asks
+//! long-running commands to stop at the next consistent point, a second one
+//! exits right away.
+
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// How long an interrupted sync may take to finish the message it's on
+/// before abandoning it.
+pub const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Exit status after an interruption, as shells report for SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// A command was stopped by a signal, after leaving the store consistent.
+#[derive(Debug, thiserror::Error)]
+#[error("interrupted, the next run resumes where this one stopped")]
+pub struct Interrupted;
+
+/// Returns a token cancelled on the first signal.
+pub fn on_signal() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    tokio::spawn(async move {
+        signal().await;
+        tracing::warn!("interrupted, finishing the current message (interrupt again to abort)");
+        cancel.cancel();
+        signal().await;
+        tracing::warn!("aborted");
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+    token
+}
+
+/// Completes once work in progress should be given up on: `GRACE_PERIOD`
+/// after cancellation.
+pub async fn grace_expired(token: &CancellationToken) {
+    token.cancelled().await;
+    tokio::time::sleep(GRACE_PERIOD).await;
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = signal(SignalKind::terminate()).expect("SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
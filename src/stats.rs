@@ -0,0 +1,212 @@
+use crate::{
+    format::format_size,
+    store::{CountStats, Stats, Store},
+};
+use clap::ValueEnum;
+use maud::{DOCTYPE, Markup, html};
+
+const BAR_WIDTH: f64 = 480.0;
+const BAR_HEIGHT: u32 = 18;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Period {
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Week => "week",
+            Period::Month => "month",
+            Period::Year => "year",
+        }
+    }
+
+    /// `strftime` format for the start of a period.
+    pub fn format(&self) -> &'static str {
+        match self {
+            Period::Week => "%Y-%m-%d",
+            Period::Month => "%Y-%m",
+            Period::Year => "%Y",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    /// A self-contained HTML page with bar charts
+    Html,
+}
+
+pub fn stats(store: &Store, period: Period, top: usize, format: Format) -> eyre::Result<()> {
+    let stats = store.stats(period.as_str(), period.format(), top)?;
+    match format {
+        Format::Table => print_tables(&stats),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+        Format::Html => println!("{}", render_html(&stats, period).into_string()),
+    }
+    Ok(())
+}
+
+fn print_tables(stats: &Stats) {
+    let count_rows = |rows: &[CountStats]| -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|r| Vec::from([r.key.clone(), r.messages.to_string(), format_size(r.bytes)]))
+            .collect()
+    };
+
+    print_table(
+        "Volume",
+        &["Period", "Messages", "Size"],
+        stats
+            .volume
+            .iter()
+            .map(|r| {
+                Vec::from([
+                    r.period.clone(),
+                    r.messages.to_string(),
+                    format_size(r.bytes),
+                ])
+            })
+            .collect(),
+    );
+    print_table(
+        "Top senders",
+        &["Sender", "Messages", "Size"],
+        count_rows(&stats.top_senders),
+    );
+    print_table(
+        "Top recipient domains",
+        &["Domain", "Messages", "Size"],
+        count_rows(&stats.top_recipient_domains),
+    );
+    print_table(
+        "Storage by label",
+        &["Label", "Messages", "Size", "Attachments"],
+        stats
+            .labels
+            .iter()
+            .map(|r| {
+                Vec::from([
+                    r.label.clone(),
+                    r.messages.to_string(),
+                    format_size(r.bytes),
+                    format_size(r.attachment_bytes),
+                ])
+            })
+            .collect(),
+    );
+    print_table(
+        "Largest messages",
+        &["Id", "Date", "Size", "Subject"],
+        stats
+            .largest_messages
+            .iter()
+            .map(|r| {
+                Vec::from([
+                    r.id.clone(),
+                    r.internal_date.format("%Y-%m-%d").to_string(),
+                    format_size(r.bytes),
+                    r.subject.clone().unwrap_or_default(),
+                ])
+            })
+            .collect(),
+    );
+    print_table(
+        "Attachment types",
+        &["MIME type", "Attachments", "Size"],
+        count_rows(&stats.attachment_types),
+    );
+}
+
+fn print_table(title: &str, headers: &[&str], rows: Vec<Vec<String>>) {
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let separator: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    println!("{title}");
+    for row in [&headers, &separator].into_iter().chain(&rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    println!();
+}
+
+pub fn render_html(stats: &Stats, period: Period) -> Markup {
+    let bars = |rows: Vec<(String, usize, String)>| -> Markup {
+        let max = rows.iter().map(|(_, v, _)| *v).max().unwrap_or(0).max(1) as f64;
+        html! {
+            table {
+                @for (label, value, text) in &rows {
+                    tr {
+                        td.label { (label) }
+                        td {
+                            svg width=(BAR_WIDTH) height=(BAR_HEIGHT) {
+                                rect width=(format!("{:.1}", *value as f64 / max * BAR_WIDTH))
+                                    height=(BAR_HEIGHT - 4) y="2" {}
+                            }
+                        }
+                        td.value { (text) }
+                    }
+                }
+            }
+            @if rows.is_empty() {
+                p { "No data." }
+            }
+        }
+    };
+    let count_bars = |rows: &[CountStats]| {
+        bars(
+            rows.iter()
+                .map(|r| (r.key.clone(), r.messages, r.messages.to_string()))
+                .collect(),
+        )
+    };
+
+    html! {
+        (DOCTYPE)
+        html {
+            head {
+                meta charset="utf-8";
+                title { "Gmail Archiver - Statistics" }
+                style {
+                    "body { font-family: sans-serif; margin: 2em; }"
+                    "td { padding: 0 0.5em; white-space: nowrap; }"
+                    "td.label { max-width: 30em; overflow: hidden; text-overflow: ellipsis; }"
+                    "td.value { text-align: right; }"
+                    "rect { fill: #4a7bd0; }"
+                }
+            }
+            body {
+                h1 { "Mailbox statistics" }
+                h2 { "Messages per " (period.as_str()) }
+                (bars(stats.volume.iter().map(|r| (r.period.clone(), r.messages, r.messages.to_string())).collect()))
+                h2 { "Top senders" }
+                (count_bars(&stats.top_senders))
+                h2 { "Top recipient domains" }
+                (count_bars(&stats.top_recipient_domains))
+                h2 { "Storage by label" }
+                (bars(stats.labels.iter().map(|r| (r.label.clone(), r.bytes, format_size(r.bytes))).collect()))
+                h2 { "Largest messages" }
+                (bars(stats.largest_messages.iter().map(|r| {
+                    let subject = r.subject.clone().unwrap_or_else(|| r.id.clone());
+                    (subject, r.bytes, format_size(r.bytes))
+                }).collect()))
+                h2 { "Attachment types" }
+                (bars(stats.attachment_types.iter().map(|r| (r.key.clone(), r.bytes, format_size(r.bytes))).collect()))
+            }
+        }
+    }
+}
@@ -1,14 +1,57 @@
 use crate::{
-    model::{Attachment, AttachmentId, FullMessage, Label, LabelId, MessageId},
-    oauth::{OAuthTokens, client::AccessTokenUpdate},
+    headers::{AddressField, ParsedHeaders},
+    mime,
+    model::{Attachment, AttachmentId, FullMessage, Header, Label, LabelId, LabelType, MessageId},
+    oauth::{AuthorizationFlow, OAuthTokens, client::AccessTokenUpdate},
+    query::CompiledQuery,
+    search::{Document, IndexablePart},
 };
 use chrono::{DateTime, Utc};
-use duckdb::{Connection, OptionalExt, params};
+use duckdb::{Connection, OptionalExt, Transaction, params, params_from_iter};
+use serde::Serialize;
 use std::{
+    collections::HashMap,
+    iter,
     path::Path,
     sync::{Arc, Mutex},
 };
 
+const CURRENT_VERSION: i32 = 8;
+
+/// The state of the stored messages matching a condition on `m`, derived
+/// from which of their contents are present. Takes the update time as its
+/// first parameter.
+const DERIVED_MESSAGE_STATE: &str = "
+    SELECT
+        id,
+        CASE
+            WHEN raw AND NOT missing_attachments THEN 'complete'
+            WHEN raw THEN 'raw'
+            WHEN NOT missing_attachments THEN 'attachments'
+            ELSE 'metadata'
+        END,
+        ?::TIMESTAMP
+    FROM (
+        SELECT
+            m.id,
+            EXISTS (SELECT 1 FROM raw_messages r WHERE r.message_id = m.id) AS raw,
+            EXISTS (
+                SELECT 1 FROM message_part_body b
+                WHERE b.message_id = m.id
+                AND b.attachment_id IS NOT NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM message_attachments a
+                    WHERE a.message_id = b.message_id AND a.attachment_id = b.attachment_id
+                )
+            ) AS missing_attachments
+        FROM messages m
+        WHERE {condition}
+    )";
+
+/// Okapi BM25 parameters.
+const BM25_K1: f64 = 1.2;
+const BM25_B: f64 = 0.75;
+
 #[derive(Clone)]
 pub struct Store {
     conn: Arc<Mutex<Connection>>,
@@ -24,96 +67,499 @@ impl Store {
     }
 
     fn init_or_migrate_db(conn: &mut Connection) -> eyre::Result<()> {
-        if let Ok(version) = conn.query_row("SELECT * FROM version", [], |row| row.get(0)) {
-            // migrate
+        let mut version = match conn.query_row("SELECT * FROM version", [], |row| row.get(0)) {
+            Ok(version) => version,
+            Err(_) => {
+                Self::init_db(conn)?;
+                0
+            }
+        };
+        while version != CURRENT_VERSION {
+            let tr = conn.transaction()?;
             match version {
-                0 => (), // current version
+                0 => Self::migrate_v0_to_v1(&tr)?,
+                1 => Self::migrate_v1_to_v2(&tr)?,
+                2 => Self::migrate_v2_to_v3(&tr)?,
+                3 => Self::migrate_v3_to_v4(&tr)?,
+                4 => Self::migrate_v4_to_v5(&tr)?,
+                5 => Self::migrate_v5_to_v6(&tr)?,
+                6 => Self::migrate_v6_to_v7(&tr)?,
+                7 => Self::migrate_v7_to_v8(&tr)?,
                 version => eyre::bail!("unrecognized database version: {version}"),
             }
-        } else {
-            // init
-            conn.execute_batch(
-                "
-                    CREATE TABLE tokens (
-                        access_token TEXT NOT NULL,
-                        refresh_token TEXT PRIMARY KEY,
-                        expires_at TIMESTAMP NOT NULL,
-                        refresh_token_expires_at TIMESTAMP
-                    );
-
-                    CREATE TYPE message_list_visibility AS ENUM ('SHOW', 'HIDE');
-                    CREATE TYPE label_list_visibility AS ENUM ('SHOW', 'SHOW_IF_UNREAD', 'HIDE');
-                    CREATE TYPE label_type AS ENUM ('SYSTEM', 'USER');
-
-                    CREATE TABLE labels (
-                        id TEXT PRIMARY KEY,
-                        name TEXT NOT NULL,
-                        message_list_visibility message_list_visibility,
-                        label_list_visibility label_list_visibility,
-                        type label_type NOT NULL,
-                        color_text TEXT,
-                        background_color TEXT
-                    );
-
-                    CREATE TABLE messages (
-                        id TEXT PRIMARY KEY,
-                        thread_id TEXT NOT NULL,
-                        snippet TEXT,
-                        history_id TEXT NOT NULL,
-                        internal_date TIMESTAMP NOT NULL,
-                        size_estimate BIGINT NOT NULL
-                    );
-
-                    CREATE TABLE message_labels (
-                        message_id TEXT,
-                        label_id TEXT,
-                        PRIMARY KEY (message_id, label_id),
-                        FOREIGN KEY (message_id) REFERENCES messages (id),
-                        FOREIGN KEY (label_id) REFERENCES labels (id)
-                    );
-
-                    CREATE TABLE message_parts (
-                        message_id TEXT NOT NULL,
-                        part_id TEXT NOT NULL,
-                        mime_type TEXT,
-                        filename TEXT,
-                        headers STRUCT(name TEXT, value TEXT)[],
-                        children TEXT[],
-                        PRIMARY KEY (message_id, part_id),
-                        FOREIGN KEY (message_id) REFERENCES messages (id)
-                    );
-
-                    CREATE TABLE message_part_body (
-                        message_id TEXT NOT NULL,
-                        part_id TEXT NOT NULL,
-                        attachment_id TEXT,
-                        size BIGINT NOT NULL,
-                        data BLOB,
-                        FOREIGN KEY (message_id, part_id)
-                            REFERENCES message_parts(message_id, part_id),
-                        FOREIGN KEY (message_id) REFERENCES messages (id)
-                    );
-
-                    CREATE TABLE message_attachments (
-                        message_id TEXT NOT NULL,
-                        attachment_id TEXT NOT NULL,
-                        size BIGINT NOT NULL,
-                        data BLOB NOT NULL,
-                        PRIMARY KEY (message_id, attachment_id),
-                        FOREIGN KEY (message_id) REFERENCES messages (id)
-                    );
-
-                    CREATE TABLE raw_messages (
-                        message_id TEXT PRIMARY KEY,
-                        data TEXT NOT NULL,
-                        FOREIGN KEY (message_id) REFERENCES messages (id)
-                    );
-
-                    CREATE TABLE version AS SELECT 0;
-                    ",
-            )?;
+            version += 1;
+            tr.execute_batch(&format!(
+                "CREATE OR REPLACE TABLE version AS SELECT {version}"
+            ))?;
+            tr.commit()?;
+            tracing::info!("database migrated to version {version}");
         }
+        Ok(())
+    }
+
+    fn init_db(conn: &Connection) -> eyre::Result<()> {
+        conn.execute_batch(
+            "
+                CREATE TABLE tokens (
+                    access_token TEXT NOT NULL,
+                    refresh_token TEXT PRIMARY KEY,
+                    expires_at TIMESTAMP NOT NULL,
+                    refresh_token_expires_at TIMESTAMP
+                );
+
+                CREATE TYPE message_list_visibility AS ENUM ('SHOW', 'HIDE');
+                CREATE TYPE label_list_visibility AS ENUM ('SHOW', 'SHOW_IF_UNREAD', 'HIDE');
+                CREATE TYPE label_type AS ENUM ('SYSTEM', 'USER');
+
+                CREATE TABLE labels (
+                    id TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    message_list_visibility message_list_visibility,
+                    label_list_visibility label_list_visibility,
+                    type label_type NOT NULL,
+                    color_text TEXT,
+                    background_color TEXT
+                );
+
+                CREATE TABLE messages (
+                    id TEXT PRIMARY KEY,
+                    thread_id TEXT NOT NULL,
+                    snippet TEXT,
+                    history_id TEXT NOT NULL,
+                    internal_date TIMESTAMP NOT NULL,
+                    size_estimate BIGINT NOT NULL
+                );
+
+                CREATE TABLE message_labels (
+                    message_id TEXT,
+                    label_id TEXT,
+                    PRIMARY KEY (message_id, label_id),
+                    FOREIGN KEY (message_id) REFERENCES messages (id),
+                    FOREIGN KEY (label_id) REFERENCES labels (id)
+                );
+
+                CREATE TABLE message_parts (
+                    message_id TEXT NOT NULL,
+                    part_id TEXT NOT NULL,
+                    mime_type TEXT,
+                    filename TEXT,
+                    headers STRUCT(name TEXT, value TEXT)[],
+                    children TEXT[],
+                    PRIMARY KEY (message_id, part_id),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TABLE message_part_body (
+                    message_id TEXT NOT NULL,
+                    part_id TEXT NOT NULL,
+                    attachment_id TEXT,
+                    size BIGINT NOT NULL,
+                    data BLOB,
+                    FOREIGN KEY (message_id, part_id)
+                        REFERENCES message_parts(message_id, part_id),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TABLE message_attachments (
+                    message_id TEXT NOT NULL,
+                    attachment_id TEXT NOT NULL,
+                    size BIGINT NOT NULL,
+                    data BLOB NOT NULL,
+                    PRIMARY KEY (message_id, attachment_id),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TABLE raw_messages (
+                    message_id TEXT PRIMARY KEY,
+                    data TEXT NOT NULL,
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TABLE version AS SELECT 0;
+                ",
+        )?;
+        Ok(())
+    }
+
+    fn migrate_v0_to_v1(tr: &Transaction) -> eyre::Result<()> {
+        tr.execute_batch(
+            "
+                CREATE TYPE search_field AS ENUM ('subject', 'from', 'to', 'body');
+
+                CREATE TABLE search_documents (
+                    message_id TEXT PRIMARY KEY,
+                    length BIGINT NOT NULL,
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TABLE search_terms (
+                    term TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    field search_field NOT NULL,
+                    frequency BIGINT NOT NULL,
+                    PRIMARY KEY (term, message_id, field),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+                ",
+        )?;
+        // backfill the index for messages stored before it existed
+        let message_ids = tr
+            .prepare("SELECT id FROM messages")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for message_id in message_ids {
+            let document = Self::load_search_document(tr, &message_id)?;
+            Self::insert_search_document(tr, &message_id, &document)?;
+        }
+        Ok(())
+    }
+
+    fn migrate_v1_to_v2(tr: &Transaction) -> eyre::Result<()> {
+        tr.execute_batch(
+            "
+                CREATE TABLE message_headers (
+                    message_id TEXT PRIMARY KEY,
+                    subject TEXT,
+                    date TIMESTAMP,
+                    internet_message_id TEXT,
+                    in_reply_to TEXT,
+                    reference_ids TEXT[],
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE TYPE address_field AS ENUM ('from', 'to', 'cc', 'bcc', 'reply_to');
+
+                CREATE TABLE message_addresses (
+                    message_id TEXT NOT NULL,
+                    field address_field NOT NULL,
+                    position INTEGER NOT NULL,
+                    display_name TEXT,
+                    address TEXT NOT NULL,
+                    PRIMARY KEY (message_id, field, position),
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+
+                CREATE INDEX message_addresses_address ON message_addresses (address);
+                ",
+        )?;
+        // fill the tables for messages stored before they existed
+        Self::fill_headers(tr)?;
+        Ok(())
+    }
+
+    /// Parses the headers of stored messages which have none in
+    /// `message_headers` yet, returning how many were parsed.
+    fn fill_headers(conn: &Connection) -> eyre::Result<usize> {
+        let stored = conn
+            .prepare(
+                "SELECT message_id, to_json(headers)::TEXT
+                FROM message_parts
+                WHERE part_id = ''
+                AND message_id NOT IN (SELECT message_id FROM message_headers)",
+            )?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Option<String>>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (message_id, headers) in &stored {
+            let headers: Vec<Header> = match headers {
+                Some(headers) => serde_json::from_str(headers)?,
+                None => Vec::new(),
+            };
+            Self::insert_headers(conn, message_id, &ParsedHeaders::parse(&headers))?;
+        }
+        Ok(stored.len())
+    }
 
+    /// Parses the headers of all stored messages again, e.g. after the
+    /// parser learned to handle more of them.
+    ///
+    /// The old rows are cleared in their own transaction, as DuckDB can't
+    /// insert a key deleted in the same transaction. If parsing fails
+    /// midway, running this again fills in the rest.
+    pub fn rebuild_headers(&self) -> eyre::Result<usize> {
+        let mut guard = self.conn.lock().unwrap();
+        let tr = guard.transaction()?;
+        tr.execute_batch("DELETE FROM message_addresses; DELETE FROM message_headers;")?;
+        tr.commit()?;
+        let tr = guard.transaction()?;
+        let count = Self::fill_headers(&tr)?;
+        tr.commit()?;
+        Ok(count)
+    }
+
+    fn migrate_v2_to_v3(tr: &Transaction) -> eyre::Result<()> {
+        tr.execute_batch(
+            "
+                CREATE TABLE correspondents (
+                    address TEXT PRIMARY KEY,
+                    display_name TEXT,
+                    first_seen TIMESTAMP NOT NULL,
+                    last_seen TIMESTAMP NOT NULL,
+                    sent_count BIGINT NOT NULL,
+                    received_count BIGINT NOT NULL
+                );
+                ",
+        )?;
+        Ok(())
+    }
+
+    fn migrate_v3_to_v4(tr: &Transaction) -> eyre::Result<()> {
+        // unknown for tokens obtained before this migration
+        tr.execute_batch("ALTER TABLE tokens ADD COLUMN scope TEXT;")?;
+        Ok(())
+    }
+
+    fn migrate_v4_to_v5(tr: &Transaction) -> eyre::Result<()> {
+        // runs without `finished_at` were interrupted
+        tr.execute_batch(
+            "
+                CREATE SEQUENCE sync_run_ids;
+
+                CREATE TABLE sync_runs (
+                    id BIGINT PRIMARY KEY DEFAULT nextval('sync_run_ids'),
+                    mode TEXT NOT NULL,
+                    started_at TIMESTAMP NOT NULL,
+                    finished_at TIMESTAMP,
+                    account TEXT,
+                    messages_added BIGINT NOT NULL DEFAULT 0,
+                    messages_updated BIGINT NOT NULL DEFAULT 0,
+                    messages_deleted BIGINT NOT NULL DEFAULT 0,
+                    attachments BIGINT NOT NULL DEFAULT 0,
+                    bytes BIGINT NOT NULL DEFAULT 0,
+                    errors BIGINT NOT NULL DEFAULT 0,
+                    history_id TEXT,
+                    error TEXT
+                );
+                ",
+        )?;
+        Ok(())
+    }
+
+    fn migrate_v5_to_v6(tr: &Transaction) -> eyre::Result<()> {
+        // no foreign key: messages are `listed` before they're stored
+        tr.execute_batch(
+            "
+                CREATE TYPE message_state AS ENUM (
+                    'listed', 'metadata', 'attachments', 'raw', 'complete'
+                );
+
+                CREATE TABLE message_states (
+                    message_id TEXT PRIMARY KEY,
+                    state message_state NOT NULL,
+                    updated_at TIMESTAMP NOT NULL
+                );
+                ",
+        )?;
+        tr.execute(
+            &format!(
+                "INSERT INTO message_states {}",
+                DERIVED_MESSAGE_STATE.replace("{condition}", "true")
+            ),
+            [Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    fn migrate_v6_to_v7(tr: &Transaction) -> eyre::Result<()> {
+        // messages removed from Gmail by a retention rule, kept in the store
+        tr.execute_batch(
+            "
+                CREATE TABLE remote_removals (
+                    message_id TEXT PRIMARY KEY,
+                    rule TEXT NOT NULL,
+                    action TEXT NOT NULL,
+                    removed_at TIMESTAMP NOT NULL,
+                    FOREIGN KEY (message_id) REFERENCES messages (id)
+                );
+                ",
+        )?;
+        Ok(())
+    }
+
+    fn migrate_v7_to_v8(tr: &Transaction) -> eyre::Result<()> {
+        // unknown for tokens obtained before this migration
+        tr.execute_batch(
+            "
+                CREATE TYPE authorization_flow AS ENUM ('browser', 'headless');
+                ALTER TABLE tokens ADD COLUMN flow authorization_flow;
+                ALTER TABLE tokens ADD COLUMN port INTEGER;
+                ",
+        )?;
+        Ok(())
+    }
+
+    /// Recomputes the state of a message from its stored contents, to be
+    /// called in the transaction writing them.
+    fn update_message_state(conn: &Connection, message_id: &str) -> eyre::Result<()> {
+        conn.execute(
+            &format!(
+                "INSERT INTO message_states {}
+                ON CONFLICT (message_id) DO UPDATE
+                SET state = excluded.state, updated_at = excluded.updated_at",
+                DERIVED_MESSAGE_STATE.replace("{condition}", "m.id = ?")
+            ),
+            params![Utc::now().to_rfc3339(), message_id],
+        )?;
+        Ok(())
+    }
+
+    fn load_search_document(conn: &Connection, message_id: &str) -> eyre::Result<Document> {
+        let parts = Self::query_message_parts(conn, message_id)?;
+        let root_headers = parts
+            .iter()
+            .find(|part| part.part_id.is_empty())
+            .map(|part| part.headers.as_slice())
+            .unwrap_or_default();
+        Ok(Document::from_parts(
+            root_headers,
+            parts.iter().map(|part| IndexablePart {
+                mime_type: &part.mime_type,
+                charset: mime::content_type(&part.headers)
+                    .param("charset")
+                    .map(str::to_owned),
+                data: part.data.as_deref(),
+            }),
+        ))
+    }
+
+    fn query_message_parts(conn: &Connection, message_id: &str) -> eyre::Result<Vec<StoredPart>> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT
+                p.part_id,
+                p.mime_type,
+                p.filename,
+                to_json(p.headers)::TEXT,
+                b.size,
+                b.data
+            FROM message_parts p
+            JOIN message_part_body b USING (message_id, part_id)
+            WHERE p.message_id = ?
+            ORDER BY p.part_id",
+        )?;
+        let mut rows = stmt.query([message_id])?;
+        let mut parts = Vec::new();
+        while let Some(row) = rows.next()? {
+            let headers: Option<String> = row.get(3)?;
+            parts.push(StoredPart {
+                part_id: row.get(0)?,
+                mime_type: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                filename: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                headers: match headers {
+                    Some(headers) => serde_json::from_str(&headers)?,
+                    None => Vec::new(),
+                },
+                size: row.get(4)?,
+                data: row.get(5)?,
+            });
+        }
+        Ok(parts)
+    }
+
+    fn insert_headers(
+        conn: &Connection,
+        message_id: &str,
+        headers: &ParsedHeaders,
+    ) -> eyre::Result<()> {
+        #[derive(Serialize)]
+        struct AddressRow<'a> {
+            field: AddressField,
+            position: usize,
+            display_name: Option<&'a str>,
+            address: &'a str,
+        }
+
+        let references = serde_json::to_string(&headers.references)?;
+        conn.execute(
+            "INSERT INTO message_headers VALUES (?, ?, ?, ?, ?, ?::JSON::TEXT[])",
+            params![
+                message_id,
+                headers.subject,
+                headers.date.map(|d| d.to_rfc3339()),
+                headers.message_id,
+                headers.in_reply_to,
+                references,
+            ],
+        )?;
+        if headers.addresses.is_empty() {
+            return Ok(());
+        }
+        let mut positions = HashMap::<AddressField, usize>::new();
+        let addresses = serde_json::to_string(
+            &headers
+                .addresses
+                .iter()
+                .map(|(field, address)| {
+                    let position = positions.entry(*field).or_default();
+                    *position += 1;
+                    AddressRow {
+                        field: *field,
+                        position: *position,
+                        display_name: address.display_name.as_deref(),
+                        address: &address.address,
+                    }
+                })
+                .collect::<Vec<_>>(),
+        )?;
+        conn.execute(
+            "INSERT INTO message_addresses
+            SELECT ?, a.field, a.position, a.display_name, a.address
+            FROM (
+                SELECT unnest(
+                    ?::JSON::STRUCT(
+                        field TEXT, position INTEGER, display_name TEXT, address TEXT
+                    )[],
+                    recursive := true
+                )
+            ) a",
+            params![message_id, addresses],
+        )?;
+        Ok(())
+    }
+
+    fn insert_search_document(
+        conn: &Connection,
+        message_id: &str,
+        document: &Document,
+    ) -> eyre::Result<()> {
+        #[derive(Serialize)]
+        struct TermRow<'a> {
+            term: &'a str,
+            field: &'static str,
+            frequency: usize,
+        }
+
+        conn.execute(
+            "INSERT INTO search_documents VALUES (?, ?)",
+            params![message_id, document.length],
+        )?;
+        if document.terms.is_empty() {
+            return Ok(());
+        }
+        // inserting row by row is very slow for the amount of terms a single
+        // message produces, so the whole batch goes in as a JSON array instead
+        let terms = serde_json::to_string(
+            &document
+                .terms
+                .iter()
+                .map(|((term, field), frequency)| TermRow {
+                    term,
+                    field: field.as_str(),
+                    frequency: *frequency,
+                })
+                .collect::<Vec<_>>(),
+        )?;
+        conn.execute(
+            "INSERT INTO search_terms
+            SELECT t.term, ?, t.field, t.frequency
+            FROM (
+                SELECT unnest(
+                    ?::JSON::STRUCT(term TEXT, field TEXT, frequency BIGINT)[],
+                    recursive := true
+                )
+            ) t",
+            params![message_id, terms],
+        )?;
         Ok(())
     }
 
@@ -122,14 +568,27 @@ impl Store {
             .conn
             .lock()
             .unwrap()
-            .query_row("SELECT * FROM tokens", [], |row| {
-                Ok(OAuthTokens {
-                    access_token: row.get::<_, String>(0)?.into(),
-                    refresh_token: row.get::<_, String>(1)?.into(),
-                    expires_at: as_datetime(row, 2)?,
-                    refresh_token_expires_at: as_datetime_optional(row, 3)?,
-                })
-            })
+            .query_row(
+                "SELECT access_token, refresh_token, expires_at, refresh_token_expires_at,
+                    scope, flow::TEXT, port
+                FROM tokens",
+                [],
+                |row| {
+                    let port = row.get(6)?;
+                    Ok(OAuthTokens {
+                        access_token: row.get::<_, String>(0)?.into(),
+                        refresh_token: row.get::<_, String>(1)?.into(),
+                        expires_at: as_datetime(row, 2)?,
+                        refresh_token_expires_at: as_datetime_optional(row, 3)?,
+                        scope: row.get(4)?,
+                        flow: match row.get::<_, Option<String>>(5)?.as_deref() {
+                            Some("headless") => Some(AuthorizationFlow::Headless { port }),
+                            Some(_) => Some(AuthorizationFlow::Browser { port }),
+                            None => None,
+                        },
+                    })
+                },
+            )
             .optional()?;
         Ok(tokens)
     }
@@ -140,17 +599,40 @@ impl Store {
             refresh_token,
             expires_at,
             refresh_token_expires_at,
+            scope,
+            flow,
         } = tokens;
+        let (flow, port) = match flow {
+            Some(AuthorizationFlow::Browser { port }) => (Some("browser"), *port),
+            Some(AuthorizationFlow::Headless { port }) => (Some("headless"), *port),
+            None => (None, None),
+        };
         tracing::debug!("token expires: {}", expires_at.to_rfc3339());
-        self.conn.lock().unwrap().execute(
-            "INSERT OR REPLACE INTO tokens VALUES (?, ?, ?, ?)",
+        let mut conn = self.conn.lock().unwrap();
+        let tr = conn.transaction()?;
+        // a new grant replaces the previous one, whose refresh token differs
+        tr.execute_batch("DELETE FROM tokens")?;
+        tr.execute(
+            "INSERT INTO tokens VALUES (?, ?, ?, ?, ?, ?, ?)",
             params![
                 access_token.as_str(),
                 refresh_token.as_str(),
                 expires_at.to_rfc3339(),
-                refresh_token_expires_at.map(|t| t.to_rfc3339())
+                refresh_token_expires_at.map(|t| t.to_rfc3339()),
+                scope,
+                flow,
+                port,
             ],
         )?;
+        tr.commit()?;
+        Ok(())
+    }
+
+    pub fn clear_tokens(&self) -> eyre::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute_batch("DELETE FROM tokens")?;
         Ok(())
     }
 
@@ -191,6 +673,36 @@ impl Store {
         Ok(())
     }
 
+    /// Finds the label with the given display name, as used by Takeout, and
+    /// creates a user label for it if there's none. System labels are also
+    /// matched by id, e.g. `Category Promotions` for `CATEGORY_PROMOTIONS`.
+    pub fn resolve_label(&self, name: &str) -> eyre::Result<LabelId> {
+        let system_id = name.to_uppercase().replace(' ', "_");
+        let existing: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id FROM labels WHERE lower(name) = lower(?) OR id = ? LIMIT 1",
+                [name, system_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = existing {
+            return Ok(id.into());
+        }
+        let label = Label {
+            id: format!("Label_import_{name}").into(),
+            name: name.to_owned(),
+            message_list_visibility: None,
+            label_list_visibility: None,
+            r#type: LabelType::User,
+            color: None,
+        };
+        self.insert_label(&label)?;
+        Ok(label.id)
+    }
+
     pub fn message_count(&self) -> eyre::Result<usize> {
         let count =
             self.conn
@@ -200,6 +712,230 @@ impl Store {
         Ok(count)
     }
 
+    pub fn message_ids(&self) -> eyre::Result<Vec<MessageId>> {
+        let ids = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare_cached("SELECT id FROM messages")?
+            .query_map([], |row| Ok(row.get::<_, String>(0)?.into()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Ids of the messages matching `query`, leaving out those that aren't
+    /// completely stored yet, oldest first.
+    pub fn complete_message_ids(&self, query: &CompiledQuery) -> eyre::Result<Vec<MessageId>> {
+        let sql = format!(
+            "SELECT m.id FROM messages m
+            JOIN message_states s ON s.message_id = m.id AND s.state = 'complete'
+            WHERE {}
+            ORDER BY m.internal_date, m.id",
+            query.condition
+        );
+        let ids = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare(&sql)?
+            .query_map(params_from_iter(&query.params), |row| {
+                Ok(row.get::<_, String>(0)?.into())
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    /// Messages matching `query` that are still in Gmail as far as the store
+    /// knows, oldest first.
+    pub fn retention_candidates(
+        &self,
+        query: &CompiledQuery,
+    ) -> eyre::Result<Vec<RetentionCandidate>> {
+        let sql = format!(
+            "SELECT m.id, m.size_estimate, coalesce(s.state = 'complete', false)
+            FROM messages m
+            LEFT JOIN message_states s ON s.message_id = m.id
+            WHERE ({})
+            AND m.id NOT IN (SELECT message_id FROM remote_removals)
+            ORDER BY m.internal_date, m.id",
+            query.condition
+        );
+        let candidates = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare(&sql)?
+            .query_map(params_from_iter(&query.params), |row| {
+                Ok(RetentionCandidate {
+                    id: row.get::<_, String>(0)?.into(),
+                    size_estimate: row.get(1)?,
+                    complete: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(candidates)
+    }
+
+    /// Records that a message was removed from Gmail by retention `rule`.
+    pub fn record_remote_removal(
+        &self,
+        id: &MessageId,
+        rule: &str,
+        action: &str,
+        removed_at: DateTime<Utc>,
+    ) -> eyre::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO remote_removals VALUES (?, ?, ?, ?)",
+            params![id.as_str(), rule, action, removed_at.to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn archive_status(&self) -> eyre::Result<ArchiveStatus> {
+        let status = self.conn.lock().unwrap().query_row(
+            "SELECT
+                (SELECT count(*) FROM messages),
+                (SELECT count(*) FROM labels),
+                (SELECT count(*) FROM raw_messages),
+                (SELECT count(*) FROM message_attachments),
+                (SELECT coalesce(sum(size), 0)::BIGINT FROM message_attachments),
+                (SELECT coalesce(sum(size_estimate), 0)::BIGINT FROM messages),
+                (SELECT min(internal_date) FROM messages),
+                (SELECT max(internal_date) FROM messages),
+                (SELECT count(*) FROM message_states WHERE state <> 'complete')",
+            [],
+            |row| {
+                Ok(ArchiveStatus {
+                    messages: row.get(0)?,
+                    labels: row.get(1)?,
+                    raw_messages: row.get(2)?,
+                    attachments: row.get(3)?,
+                    attachment_bytes: row.get(4)?,
+                    size_estimate: row.get(5)?,
+                    oldest: as_datetime_optional(row, 6)?,
+                    newest: as_datetime_optional(row, 7)?,
+                    partial_messages: row.get(8)?,
+                })
+            },
+        )?;
+        Ok(status)
+    }
+
+    /// Records the start of a sync run, returning its id.
+    pub fn start_sync_run(&self, mode: &str, started_at: DateTime<Utc>) -> eyre::Result<i64> {
+        let id = self.conn.lock().unwrap().query_row(
+            "INSERT INTO sync_runs (mode, started_at) VALUES (?, ?) RETURNING id",
+            params![mode, started_at.to_rfc3339()],
+            |row| row.get(0),
+        )?;
+        Ok(id)
+    }
+
+    pub fn finish_sync_run(
+        &self,
+        id: i64,
+        finished_at: DateTime<Utc>,
+        counts: &SyncRunCounts,
+        error: Option<&str>,
+    ) -> eyre::Result<()> {
+        let SyncRunCounts {
+            account,
+            messages_added,
+            messages_updated,
+            messages_deleted,
+            attachments,
+            bytes,
+            errors,
+            history_id,
+        } = counts;
+        self.conn.lock().unwrap().execute(
+            "UPDATE sync_runs SET
+                finished_at = ?,
+                account = ?,
+                messages_added = ?,
+                messages_updated = ?,
+                messages_deleted = ?,
+                attachments = ?,
+                bytes = ?,
+                errors = ?,
+                history_id = ?,
+                error = ?
+            WHERE id = ?",
+            params![
+                finished_at.to_rfc3339(),
+                account,
+                messages_added,
+                messages_updated,
+                messages_deleted,
+                attachments,
+                bytes,
+                errors,
+                history_id,
+                error,
+                id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The most recent runs first.
+    pub fn sync_runs(&self, limit: usize) -> eyre::Result<Vec<SyncRun>> {
+        let runs = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare_cached(&format!(
+                "SELECT {SYNC_RUN_COLUMNS} FROM sync_runs ORDER BY started_at DESC LIMIT ?"
+            ))?
+            .query_map([limit], sync_run_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(runs)
+    }
+
+    /// The last run that completed without error.
+    pub fn last_successful_sync_run(&self) -> eyre::Result<Option<SyncRun>> {
+        let run = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                &format!(
+                    "SELECT {SYNC_RUN_COLUMNS} FROM sync_runs
+                    WHERE finished_at IS NOT NULL AND error IS NULL
+                    ORDER BY started_at DESC
+                    LIMIT 1"
+                ),
+                [],
+                sync_run_from_row,
+            )
+            .optional()?;
+        Ok(run)
+    }
+
+    /// Records a message seen when listing the mailbox, unless it's known
+    /// already.
+    pub fn mark_listed(&self, id: &MessageId) -> eyre::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO message_states VALUES (?, 'listed', ?) ON CONFLICT DO NOTHING",
+            params![id.as_str(), Utc::now().to_rfc3339()],
+        )?;
+        Ok(())
+    }
+
+    pub fn message_state(&self, id: &MessageId) -> eyre::Result<Option<MessageState>> {
+        let state = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT state::TEXT FROM message_states WHERE message_id = ?",
+                [id.as_str()],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        Ok(state.map(|state| MessageState::parse(&state)))
+    }
+
     pub fn contains_message(&self, id: &MessageId) -> eyre::Result<bool> {
         let count: usize = self.conn.lock().unwrap().query_row(
             "SELECT count(*) FROM messages WHERE id = ?",
@@ -266,17 +1002,71 @@ impl Store {
                 ],
             )?;
         }
+        Self::insert_headers(
+            &tr,
+            message.id.as_str(),
+            &ParsedHeaders::parse(&message.payload.headers),
+        )?;
+        Self::insert_search_document(&tr, message.id.as_str(), &Document::from_message(message))?;
+        Self::update_message_state(&tr, message.id.as_str())?;
         tr.commit()?;
         Ok(())
     }
 
+    /// Removes a message and everything stored with it.
+    ///
+    /// DuckDB checks foreign keys against the state before the transaction,
+    /// so rows can't be deleted in the same transaction as rows referencing
+    /// them. Each level of references goes in its own transaction instead,
+    /// the state first. If a later one fails, the `messages` row is left
+    /// without a state: sync deletes such rows again before fetching the
+    /// message anew.
+    pub fn delete_message(&self, id: &MessageId) -> eyre::Result<()> {
+        let mut guard = self.conn.lock().unwrap();
+        let tr = guard.transaction()?;
+        for table in [
+            "message_states",
+            "message_labels",
+            "message_part_body",
+            "message_attachments",
+            "raw_messages",
+            "search_terms",
+            "search_documents",
+            "message_headers",
+            "message_addresses",
+            "remote_removals",
+        ] {
+            tr.execute(
+                &format!("DELETE FROM {table} WHERE message_id = ?"),
+                [id.as_str()],
+            )?;
+        }
+        tr.commit()?;
+        // referenced by `message_part_body`
+        guard.execute(
+            "DELETE FROM message_parts WHERE message_id = ?",
+            [id.as_str()],
+        )?;
+        guard.execute("DELETE FROM messages WHERE id = ?", [id.as_str()])?;
+        Ok(())
+    }
+
+    /// Writes everything to the database file, so that nothing is left to
+    /// replay from the write-ahead log on the next open.
+    pub fn checkpoint(&self) -> eyre::Result<()> {
+        self.conn.lock().unwrap().execute_batch("CHECKPOINT")?;
+        Ok(())
+    }
+
     pub fn insert_attachment(
         &self,
         message_id: &MessageId,
         attachment_id: &AttachmentId,
         attachment: &Attachment,
     ) -> eyre::Result<()> {
-        self.conn.lock().unwrap().execute(
+        let mut guard = self.conn.lock().unwrap();
+        let tr = guard.transaction()?;
+        tr.execute(
             "INSERT INTO message_attachments VALUES (?, ?, ?, ?)",
             params![
                 message_id.as_str(),
@@ -285,6 +1075,8 @@ impl Store {
                 attachment.data
             ],
         )?;
+        Self::update_message_state(&tr, message_id.as_str())?;
+        tr.commit()?;
         Ok(())
     }
 
@@ -319,13 +1111,333 @@ impl Store {
     }
 
     pub fn insert_raw_message(&self, message_id: &MessageId, data: &[u8]) -> eyre::Result<()> {
-        self.conn.lock().unwrap().execute(
+        let mut guard = self.conn.lock().unwrap();
+        let tr = guard.transaction()?;
+        tr.execute(
             "INSERT INTO raw_messages VALUES (?, ?)",
             params![message_id.as_str(), data],
         )?;
+        Self::update_message_state(&tr, message_id.as_str())?;
+        tr.commit()?;
         Ok(())
     }
 
+    /// Recomputes the `correspondents` table from the From, To and Cc
+    /// addresses across the archive. Counts are from the correspondent's
+    /// perspective: `sent_count` is how many messages they sent.
+    pub fn rebuild_correspondents(&self) -> eyre::Result<usize> {
+        let mut guard = self.conn.lock().unwrap();
+        let tr = guard.transaction()?;
+        tr.execute("DELETE FROM correspondents", [])?;
+        let count = tr.execute(
+            "INSERT INTO correspondents
+            SELECT
+                a.address,
+                mode(a.display_name) FILTER (WHERE a.display_name IS NOT NULL),
+                min(m.internal_date),
+                max(m.internal_date),
+                count(DISTINCT a.message_id) FILTER (WHERE a.field = 'from'),
+                count(DISTINCT a.message_id) FILTER (WHERE a.field IN ('to', 'cc'))
+            FROM message_addresses a
+            JOIN messages m ON m.id = a.message_id
+            WHERE a.field IN ('from', 'to', 'cc')
+            GROUP BY a.address",
+            [],
+        )?;
+        tr.commit()?;
+        Ok(count)
+    }
+
+    /// Correspondents ordered by how many messages were exchanged with them.
+    pub fn correspondents(&self, limit: Option<usize>) -> eyre::Result<Vec<Correspondent>> {
+        let correspondents = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare_cached(
+                "SELECT address, display_name, first_seen, last_seen, sent_count, received_count
+                FROM correspondents
+                ORDER BY sent_count + received_count DESC, address
+                LIMIT ?",
+            )?
+            .query_map([limit.map(|l| l as i64)], |row| {
+                Ok(Correspondent {
+                    address: row.get(0)?,
+                    display_name: row.get(1)?,
+                    first_seen: as_datetime(row, 2)?,
+                    last_seen: as_datetime(row, 3)?,
+                    sent_count: row.get(4)?,
+                    received_count: row.get(5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(correspondents)
+    }
+
+    /// Volume per `period`, a `date_trunc` unit, labelled with the start of
+    /// each period formatted by `strftime` with `period_format`, and the
+    /// `top` senders, recipient domains and labels.
+    pub fn stats(&self, period: &str, period_format: &str, top: usize) -> eyre::Result<Stats> {
+        let conn = self.conn.lock().unwrap();
+        let volume = conn
+            .prepare_cached(
+                "SELECT
+                    strftime(date_trunc(?, internal_date), ?),
+                    count(*),
+                    sum(size_estimate)::BIGINT
+                FROM messages
+                GROUP BY 1
+                ORDER BY 1",
+            )?
+            .query_map([period, period_format], |row| {
+                Ok(VolumeStats {
+                    period: row.get(0)?,
+                    messages: row.get(1)?,
+                    bytes: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let count_by = |sql: &str| -> eyre::Result<Vec<CountStats>> {
+            let rows = conn
+                .prepare_cached(sql)?
+                .query_map([top], |row| {
+                    Ok(CountStats {
+                        key: row.get(0)?,
+                        messages: row.get(1)?,
+                        bytes: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        };
+        let top_senders = count_by(
+            "SELECT a.address, count(*), sum(m.size_estimate)::BIGINT
+            FROM message_addresses a
+            JOIN messages m ON m.id = a.message_id
+            WHERE a.field = 'from'
+            GROUP BY 1
+            ORDER BY 2 DESC, 1
+            LIMIT ?",
+        )?;
+        let top_recipient_domains = count_by(
+            "SELECT d.domain, count(*), sum(m.size_estimate)::BIGINT
+            FROM (
+                SELECT DISTINCT split_part(address, '@', 2) AS domain, message_id
+                FROM message_addresses
+                WHERE field IN ('to', 'cc', 'bcc')
+            ) d
+            JOIN messages m ON m.id = d.message_id
+            GROUP BY 1
+            ORDER BY 2 DESC, 1
+            LIMIT ?",
+        )?;
+        let labels = conn
+            .prepare_cached(
+                "SELECT
+                    coalesce(l.name, ml.label_id),
+                    count(*),
+                    sum(m.size_estimate)::BIGINT,
+                    coalesce(sum(a.bytes), 0)::BIGINT
+                FROM message_labels ml
+                JOIN messages m ON m.id = ml.message_id
+                LEFT JOIN labels l ON l.id = ml.label_id
+                LEFT JOIN (
+                    SELECT message_id, sum(size) AS bytes
+                    FROM message_attachments
+                    GROUP BY message_id
+                ) a ON a.message_id = m.id
+                GROUP BY 1
+                ORDER BY 3 DESC, 1",
+            )?
+            .query_map([], |row| {
+                Ok(LabelStats {
+                    label: row.get(0)?,
+                    messages: row.get(1)?,
+                    bytes: row.get(2)?,
+                    attachment_bytes: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let largest_messages = conn
+            .prepare_cached(
+                "SELECT m.id, m.internal_date, h.subject, m.size_estimate
+                FROM messages m
+                LEFT JOIN message_headers h ON h.message_id = m.id
+                ORDER BY m.size_estimate DESC, m.id
+                LIMIT ?",
+            )?
+            .query_map([top], |row| {
+                Ok(LargeMessage {
+                    id: row.get(0)?,
+                    internal_date: as_datetime(row, 1)?,
+                    subject: row.get(2)?,
+                    bytes: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let attachment_types = count_by(
+            "SELECT lower(p.mime_type), count(*), sum(b.size)::BIGINT
+            FROM message_parts p
+            JOIN message_part_body b USING (message_id, part_id)
+            WHERE b.attachment_id IS NOT NULL
+            GROUP BY 1
+            ORDER BY 3 DESC, 1
+            LIMIT ?",
+        )?;
+        Ok(Stats {
+            volume,
+            top_senders,
+            top_recipient_domains,
+            labels,
+            largest_messages,
+            attachment_types,
+        })
+    }
+
+    /// Finds messages matching `query`, ranked by BM25 over its free-text
+    /// terms, with hits in the subject and sender weighted higher than hits
+    /// in the body. Ties, including queries without free text, are broken by
+    /// recency.
+    pub fn search(&self, query: &CompiledQuery, limit: usize) -> eyre::Result<Vec<SearchHit>> {
+        let ranking_terms = serde_json::to_string(&query.ranking_terms)?;
+        let sql = format!(
+            "WITH ranking AS (
+                SELECT DISTINCT unnest(?::JSON::TEXT[]) AS term
+            ),
+            candidates AS (
+                SELECT m.id AS message_id FROM messages m WHERE {condition}
+            ),
+            corpus AS (
+                SELECT count(*) AS total, avg(length) AS avg_length
+                FROM search_documents
+            ),
+            df AS (
+                SELECT term, count(DISTINCT message_id) AS n
+                FROM search_terms
+                JOIN ranking USING (term)
+                GROUP BY term
+            ),
+            matches AS (
+                SELECT
+                    t.term,
+                    t.message_id,
+                    sum(t.frequency * CASE t.field
+                        WHEN 'subject' THEN 3
+                        WHEN 'from' THEN 2
+                        WHEN 'to' THEN 2
+                        ELSE 1
+                    END) AS frequency
+                FROM search_terms t
+                JOIN ranking USING (term)
+                JOIN candidates USING (message_id)
+                GROUP BY ALL
+            ),
+            scored AS (
+                SELECT
+                    m.message_id,
+                    sum(
+                        ln(1 + (c.total - df.n + 0.5) / (df.n + 0.5))
+                        * m.frequency * ({BM25_K1} + 1)
+                        / (m.frequency + {BM25_K1} * (1 - {BM25_B} + {BM25_B} * d.length / c.avg_length))
+                    ) AS score
+                FROM matches m
+                JOIN df USING (term)
+                JOIN search_documents d USING (message_id)
+                CROSS JOIN corpus c
+                GROUP BY m.message_id
+            )
+            SELECT
+                c.message_id,
+                msg.internal_date,
+                h.subject,
+                (
+                    SELECT coalesce(a.display_name || ' <' || a.address || '>', a.address)
+                    FROM message_addresses a
+                    WHERE a.message_id = c.message_id AND a.field = 'from'
+                    ORDER BY a.position
+                    LIMIT 1
+                ),
+                msg.snippet,
+                coalesce(s.score, 0)
+            FROM candidates c
+            JOIN messages msg ON msg.id = c.message_id
+            LEFT JOIN scored s USING (message_id)
+            LEFT JOIN message_headers h ON h.message_id = c.message_id
+            ORDER BY 6 DESC, msg.internal_date DESC
+            LIMIT {limit}",
+            condition = query.condition,
+        );
+        let hits = self
+            .conn
+            .lock()
+            .unwrap()
+            .prepare(&sql)?
+            .query_map(
+                params_from_iter(iter::once(&ranking_terms).chain(&query.params)),
+                |row| {
+                    Ok(SearchHit {
+                        message_id: row.get::<_, String>(0)?.into(),
+                        internal_date: as_datetime(row, 1)?,
+                        subject: row.get(2)?,
+                        from: row.get(3)?,
+                        snippet: row.get(4)?,
+                        score: row.get(5)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hits)
+    }
+
+    pub fn message_summary(&self, id: &MessageId) -> eyre::Result<Option<MessageSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let summary = conn
+            .query_row(
+                "SELECT
+                    m.internal_date,
+                    list(coalesce(l.name, ml.label_id) ORDER BY l.name)
+                        FILTER (WHERE ml.label_id IS NOT NULL)::JSON::TEXT
+                FROM messages m
+                LEFT JOIN message_labels ml ON ml.message_id = m.id
+                LEFT JOIN labels l ON l.id = ml.label_id
+                WHERE m.id = ?
+                GROUP BY ALL",
+                [id.as_str()],
+                |row| Ok((as_datetime(row, 0)?, row.get::<_, Option<String>>(1)?)),
+            )
+            .optional()?;
+        let Some((internal_date, labels)) = summary else {
+            return Ok(None);
+        };
+        Ok(Some(MessageSummary {
+            internal_date,
+            labels: match labels {
+                Some(labels) => serde_json::from_str(&labels)?,
+                None => Vec::new(),
+            },
+        }))
+    }
+
+    pub fn message_parts(&self, message_id: &MessageId) -> eyre::Result<Vec<StoredPart>> {
+        Self::query_message_parts(&self.conn.lock().unwrap(), message_id.as_str())
+    }
+
+    pub fn raw_message(&self, message_id: &MessageId) -> eyre::Result<Option<Vec<u8>>> {
+        // stored as text, but casting back to a blob undoes the escaping of
+        // any non-UTF-8 bytes
+        let data = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data::BLOB FROM raw_messages WHERE message_id = ?",
+                [message_id.as_str()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(data)
+    }
+
     pub fn contains_raw_message(&self, message_id: &MessageId) -> eyre::Result<bool> {
         let count: usize = self.conn.lock().unwrap().query_row(
             "SELECT count(*) FROM raw_messages WHERE message_id = ?",
@@ -336,6 +1448,177 @@ impl Store {
     }
 }
 
+pub struct ArchiveStatus {
+    pub messages: usize,
+    pub labels: usize,
+    pub raw_messages: usize,
+    pub attachments: usize,
+    pub attachment_bytes: usize,
+    pub size_estimate: usize,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    /// Listed or stored, but not complete yet.
+    pub partial_messages: usize,
+}
+
+pub struct RetentionCandidate {
+    pub id: MessageId,
+    pub size_estimate: usize,
+    /// Only complete messages may be removed from Gmail.
+    pub complete: bool,
+}
+
+/// How far a message got in being stored: each write of its contents
+/// updates the state in the same transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageState {
+    /// Seen when listing the mailbox, nothing stored.
+    Listed,
+    /// Stored, with attachments missing and no raw message.
+    Metadata,
+    /// With all attachments, but no raw message.
+    Attachments,
+    /// With the raw message, but attachments missing.
+    Raw,
+    Complete,
+}
+
+impl MessageState {
+    fn parse(state: &str) -> Self {
+        match state {
+            "listed" => MessageState::Listed,
+            "metadata" => MessageState::Metadata,
+            "attachments" => MessageState::Attachments,
+            "raw" => MessageState::Raw,
+            "complete" => MessageState::Complete,
+            state => unreachable!("message state enum has no {state}"),
+        }
+    }
+}
+
+/// What a sync run did, as far as it got.
+#[derive(Debug, Default)]
+pub struct SyncRunCounts {
+    pub account: Option<String>,
+    pub messages_added: usize,
+    /// Already stored messages whose missing attachments or raw message were
+    /// fetched.
+    pub messages_updated: usize,
+    /// Listed messages that were gone by the time they were fetched.
+    pub messages_deleted: usize,
+    pub attachments: usize,
+    pub bytes: u64,
+    /// Failed requests, retried or not.
+    pub errors: u64,
+    /// Mailbox history id when the run started.
+    pub history_id: Option<String>,
+}
+
+pub struct SyncRun {
+    pub id: i64,
+    pub mode: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub counts: SyncRunCounts,
+    pub error: Option<String>,
+}
+
+pub struct MessageSummary {
+    pub internal_date: DateTime<Utc>,
+    pub labels: Vec<String>,
+}
+
+pub struct StoredPart {
+    pub part_id: String,
+    pub mime_type: String,
+    pub filename: String,
+    pub headers: Vec<Header>,
+    pub size: usize,
+    pub data: Option<Vec<u8>>,
+}
+
+pub struct Correspondent {
+    pub address: String,
+    pub display_name: Option<String>,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub sent_count: usize,
+    pub received_count: usize,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub volume: Vec<VolumeStats>,
+    pub top_senders: Vec<CountStats>,
+    pub top_recipient_domains: Vec<CountStats>,
+    pub labels: Vec<LabelStats>,
+    pub largest_messages: Vec<LargeMessage>,
+    pub attachment_types: Vec<CountStats>,
+}
+
+#[derive(Serialize)]
+pub struct VolumeStats {
+    pub period: String,
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct CountStats {
+    pub key: String,
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct LabelStats {
+    pub label: String,
+    pub messages: usize,
+    /// Sum of Gmail's size estimates, which include attachments.
+    pub bytes: usize,
+    pub attachment_bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct LargeMessage {
+    pub id: String,
+    pub internal_date: DateTime<Utc>,
+    pub subject: Option<String>,
+    pub bytes: usize,
+}
+
+pub struct SearchHit {
+    pub message_id: MessageId,
+    pub internal_date: DateTime<Utc>,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    pub snippet: Option<String>,
+    pub score: f64,
+}
+
+const SYNC_RUN_COLUMNS: &str = "id, mode, started_at, finished_at, account, messages_added, \
+    messages_updated, messages_deleted, attachments, bytes, errors, history_id, error";
+
+fn sync_run_from_row(row: &duckdb::Row) -> duckdb::Result<SyncRun> {
+    Ok(SyncRun {
+        id: row.get(0)?,
+        mode: row.get(1)?,
+        started_at: as_datetime(row, 2)?,
+        finished_at: as_datetime_optional(row, 3)?,
+        counts: SyncRunCounts {
+            account: row.get(4)?,
+            messages_added: row.get(5)?,
+            messages_updated: row.get(6)?,
+            messages_deleted: row.get(7)?,
+            attachments: row.get(8)?,
+            bytes: row.get(9)?,
+            errors: row.get(10)?,
+            history_id: row.get(11)?,
+        },
+        error: row.get(12)?,
+    })
+}
+
 fn as_datetime(row: &duckdb::Row, idx: usize) -> duckdb::Result<DateTime<Utc>> {
     let val = row.get(idx)?;
     DateTime::from_timestamp_micros(val)
@@ -350,3 +1633,144 @@ fn as_datetime_optional(row: &duckdb::Row, idx: usize) -> duckdb::Result<Option<
         || duckdb::Error::IntegralValueOutOfRange(idx, val.into()),
     )?))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str) -> FullMessage {
+        let message = serde_json::json!({
+            "id": id,
+            "threadId": id,
+            "snippet": "Hello",
+            "historyId": "1000",
+            "internalDate": "1748779200000",
+            "sizeEstimate": 120,
+            "payload": {
+                "partId": "",
+                "mimeType": "text/plain",
+                "filename": "",
+                "headers": [
+                    { "name": "From", "value": "Alice <alice@example.com>" },
+                    { "name": "To", "value": "me@example.com" },
+                    { "name": "Subject", "value": "Hello" },
+                ],
+                "body": { "size": 5, "data": "SGVsbG8=" },
+            },
+        });
+        // borrows strings, so it can't deserialize from a `Value`
+        serde_json::from_str(&message.to_string()).unwrap()
+    }
+
+    #[test]
+    fn deletes_a_stored_message() {
+        let store = Store::open(":memory:").unwrap();
+        let id = MessageId::from("a".to_owned());
+        store.insert_message(&message("a")).unwrap();
+        store
+            .insert_raw_message(&id, b"Subject: Hello\r\n\r\nHello")
+            .unwrap();
+        store.insert_message(&message("b")).unwrap();
+        assert_eq!(
+            store.message_state(&id).unwrap(),
+            Some(MessageState::Complete)
+        );
+
+        store.delete_message(&id).unwrap();
+        assert!(!store.contains_message(&id).unwrap());
+        assert_eq!(store.message_state(&id).unwrap(), None);
+        assert_eq!(store.message_count().unwrap(), 1);
+        // and it can be stored again
+        store.insert_message(&message("a")).unwrap();
+        assert_eq!(store.message_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn stores_the_authorization_flow_with_tokens() {
+        let store = Store::open(":memory:").unwrap();
+        let flow = AuthorizationFlow::Headless { port: Some(8085) };
+        store
+            .set_tokens(&OAuthTokens {
+                access_token: "access".to_owned().into(),
+                refresh_token: "refresh".to_owned().into(),
+                expires_at: Utc::now(),
+                refresh_token_expires_at: None,
+                scope: None,
+                flow: Some(flow),
+            })
+            .unwrap();
+        assert_eq!(store.load_tokens().unwrap().unwrap().flow, Some(flow));
+    }
+
+    #[test]
+    fn finishes_a_partial_deletion() {
+        let store = Store::open(":memory:").unwrap();
+        let id = MessageId::from("a".to_owned());
+        store.insert_message(&message("a")).unwrap();
+        // as if deleting stopped after its first transaction
+        store
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM message_states WHERE message_id = 'a'", [])
+            .unwrap();
+        assert_eq!(store.message_state(&id).unwrap(), None);
+        assert!(store.contains_message(&id).unwrap());
+
+        store.delete_message(&id).unwrap();
+        assert!(!store.contains_message(&id).unwrap());
+        store.insert_message(&message("a")).unwrap();
+        assert!(store.message_state(&id).unwrap().is_some());
+    }
+
+    #[test]
+    fn migrates_headers_of_existing_messages() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        Store::init_db(&conn).unwrap();
+        {
+            let tr = conn.transaction().unwrap();
+            Store::migrate_v0_to_v1(&tr).unwrap();
+            tr.execute_batch(
+                "
+                    CREATE OR REPLACE TABLE version AS SELECT 1;
+                    INSERT INTO messages
+                    VALUES ('a', 'a', 'Hello', '1000', '2025-06-01 12:00:00', 120);
+                    INSERT INTO message_parts VALUES ('a', '', 'text/plain', '', [
+                        {'name': 'From', 'value': 'Alice <alice@example.com>'},
+                        {'name': 'Subject', 'value': 'Hello'}
+                    ], []);
+                    ",
+            )
+            .unwrap();
+            tr.commit().unwrap();
+        }
+
+        Store::init_or_migrate_db(&mut conn).unwrap();
+        let subject: String = conn
+            .query_row("SELECT subject FROM message_headers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(subject, "Hello");
+        let sender: String = conn
+            .query_row(
+                "SELECT address FROM message_addresses WHERE field = 'from'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(sender, "alice@example.com");
+    }
+
+    #[test]
+    fn rebuilds_headers() {
+        let store = Store::open(":memory:").unwrap();
+        store.insert_message(&message("a")).unwrap();
+        store.insert_message(&message("b")).unwrap();
+
+        assert_eq!(store.rebuild_headers().unwrap(), 2);
+        let conn = store.conn.lock().unwrap();
+        let headers: usize = conn
+            .query_row("SELECT count(*) FROM message_headers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(headers, 2);
+    }
+}
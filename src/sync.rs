@@ -0,0 +1,315 @@
+use crate::{
+    client::GmailClient,
+    config::SyncConfig,
+    http::{FailureKind, error::DefaultError, is_failure},
+    metrics::METRICS,
+    model::{AttachmentId, FullMessage, MessageId},
+    progress::{Progress, ProgressFormat},
+    shutdown::{self, Interrupted},
+    store::{MessageState, Store, SyncRunCounts},
+};
+use chrono::Utc;
+use futures_util::stream::FuturesUnordered;
+use std::collections::HashSet;
+use tokio_stream::StreamExt;
+use tokio_util::sync::CancellationToken;
+use tracing::Level;
+
+/// How many missing message ids `check` lists before summarizing.
+const MAX_REPORTED_MISSING: usize = 20;
+
+/// Runs a sync, recording it in the store's run history whether it
+/// succeeds or not. Once `cancel` is cancelled no new message is started,
+/// and the sync fails with `Interrupted`.
+pub async fn fetch_everything(
+    client: &GmailClient,
+    store: &Store,
+    config: &SyncConfig,
+    progress: ProgressFormat,
+    cancel: &CancellationToken,
+) -> eyre::Result<()> {
+    let run_id = store.start_sync_run("full", Utc::now())?;
+    let (bytes, retries) = (METRICS.bytes.get(), METRICS.retries.get());
+    let mut counts = SyncRunCounts::default();
+    let result = async {
+        fetch_labels(client, store).await?;
+        fetch_messages(client, store, config, progress, cancel, &mut counts).await
+    }
+    .await;
+    counts.bytes = METRICS.bytes.get() - bytes;
+    counts.errors = METRICS.retries.get() - retries + u64::from(result.is_err());
+    let error = result.as_ref().err().map(|err| format!("{err:#}"));
+    store.finish_sync_run(run_id, Utc::now(), &counts, error.as_deref())?;
+    if cancel.is_cancelled() {
+        store.checkpoint()?;
+    }
+    result
+}
+
+async fn fetch_labels(client: &GmailClient, store: &Store) -> eyre::Result<()> {
+    let labels = client.list_labels().await?;
+    tracing::info!("processing {} labels", labels.labels.len());
+    for label in labels.labels {
+        if store.contains_label(&label.id)? {
+            tracing::debug!(id = %label.id, "label already stored");
+            continue;
+        }
+        tracing::debug!(id = %label.id, "fetching label from remote");
+        let label = client.label(&label.id).await?;
+        METRICS.store("label", || store.insert_label(&label))?;
+        tracing::debug!(id = %label.id, "label stored successfully");
+    }
+    Ok(())
+}
+
+/// What syncing a message changed in the store.
+enum Fetched {
+    Added {
+        attachments: usize,
+    },
+    /// Was stored already, but missing attachments or the raw message.
+    Updated {
+        attachments: usize,
+    },
+    Unchanged,
+    /// Deleted remotely since it was listed.
+    Deleted,
+}
+
+async fn fetch_messages(
+    client: &GmailClient,
+    store: &Store,
+    config: &SyncConfig,
+    progress: ProgressFormat,
+    cancel: &CancellationToken,
+    counts: &mut SyncRunCounts,
+) -> eyre::Result<()> {
+    let profile = client.profile().await?;
+    let total = profile.messages_total;
+    let stored = store.message_count()?;
+    tracing::info!("total messages: {total}, stored: {stored}");
+    counts.account = Some(profile.email_address);
+    counts.history_id = Some(profile.history_id);
+    let concurrency = config.concurrency.unwrap_or(1).max(1);
+    let filter = config.filter();
+    // the mailbox total says nothing about how many messages match a filter
+    let mut progress = Progress::new(progress, filter.is_none().then_some(total));
+    let mut messages = client.list_messages(filter);
+    let mut listing_done = false;
+    let mut in_flight = FuturesUnordered::new();
+    // partial messages are resumed by the next sync, but new ones may not be
+    // listed again if they're deleted meanwhile, so they're rolled back
+    // when abandoned
+    let mut new_in_flight = HashSet::new();
+    let grace_expired = shutdown::grace_expired(cancel);
+    tokio::pin!(grace_expired);
+    while !listing_done || !in_flight.is_empty() {
+        tokio::select! {
+            biased;
+            () = &mut grace_expired, if !in_flight.is_empty() => {
+                drop(in_flight);
+                for id in new_in_flight.into_iter().map(MessageId::from) {
+                    if store.contains_message(&id)? {
+                        tracing::warn!(%id, "rolling back partially stored message");
+                        store.delete_message(&id)?;
+                    }
+                }
+                progress.finish();
+                return Err(Interrupted.into());
+            }
+            Some((id, fetched)) = in_flight.next() => {
+                new_in_flight.remove(&id);
+                match fetched? {
+                    Fetched::Added { attachments } => {
+                        counts.messages_added += 1;
+                        counts.attachments += attachments;
+                    }
+                    Fetched::Updated { attachments } => {
+                        counts.messages_updated += 1;
+                        counts.attachments += attachments;
+                    }
+                    Fetched::Unchanged => (),
+                    Fetched::Deleted => counts.messages_deleted += 1,
+                }
+                progress.message_processed();
+            }
+            () = cancel.cancelled(), if in_flight.is_empty() => {
+                progress.finish();
+                return Err(Interrupted.into());
+            }
+            message = messages.next(),
+                if !listing_done && in_flight.len() < concurrency && !cancel.is_cancelled() =>
+            {
+                let Some(message) = message.transpose()? else {
+                    listing_done = true;
+                    continue;
+                };
+                let state = match store.message_state(&message.id)? {
+                    Some(MessageState::Complete) => {
+                        tracing::debug!(id = %message.id, "message already stored");
+                        progress.message_processed();
+                        continue;
+                    }
+                    Some(state) => state,
+                    None => {
+                        // left over from a deletion that stopped midway
+                        if store.contains_message(&message.id)? {
+                            tracing::warn!(id = %message.id, "removing partially deleted message");
+                            store.delete_message(&message.id)?;
+                        }
+                        store.mark_listed(&message.id)?;
+                        MessageState::Listed
+                    }
+                };
+                if state == MessageState::Listed {
+                    new_in_flight.insert(message.id.to_string());
+                }
+                in_flight.push(async move {
+                    let fetched = fetch_message(client, store, &message.id, state).await;
+                    (message.id.to_string(), fetched)
+                });
+            }
+        }
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// Fetches whatever `state` says is missing of a message: its metadata and
+/// body, then attachments and the raw message.
+async fn fetch_message(
+    client: &GmailClient,
+    store: &Store,
+    id: &MessageId,
+    state: MessageState,
+) -> eyre::Result<Fetched> {
+    let mut attachments = 0;
+    let attachment_ids = match state {
+        MessageState::Listed => {
+            let message = match client.full_message(id).await {
+                Ok(message) => message,
+                Err(err) if is_failure::<DefaultError>(&err, FailureKind::NotFound) => {
+                    tracing::warn!(%id, "message deleted since listing, skipping");
+                    store.delete_message(id)?;
+                    return Ok(Fetched::Deleted);
+                }
+                Err(err) => return Err(err),
+            };
+            METRICS.store("message", || store.insert_message(&message))?;
+            tracing::debug!(%id, "message stored successfully");
+            extract_attachment_ids(&message)
+                .into_iter()
+                .cloned()
+                .collect()
+        }
+        MessageState::Metadata | MessageState::Raw => store.attachment_ids(id)?,
+        MessageState::Attachments | MessageState::Complete => Vec::new(),
+    };
+    for attachment_id in &attachment_ids {
+        if fetch_attachment(client, store, id, attachment_id).await? {
+            attachments += 1;
+        }
+    }
+    let raw_missing = matches!(
+        state,
+        MessageState::Listed | MessageState::Metadata | MessageState::Attachments
+    );
+    if raw_missing {
+        let raw_message = client.raw_message(id).await?;
+        METRICS.store("raw_message", || {
+            store.insert_raw_message(id, &raw_message.raw)
+        })?;
+        tracing::debug!(%id, "raw message stored successfully");
+    }
+    Ok(match state {
+        MessageState::Listed => Fetched::Added { attachments },
+        _ if attachments > 0 || raw_missing => Fetched::Updated { attachments },
+        _ => Fetched::Unchanged,
+    })
+}
+
+#[tracing::instrument(level = Level::DEBUG, skip_all, fields(msg_id = %message_id, id = %attachment_id))]
+async fn fetch_attachment(
+    client: &GmailClient,
+    store: &Store,
+    message_id: &MessageId,
+    attachment_id: &AttachmentId,
+) -> eyre::Result<bool> {
+    if store.contains_message_attachment(message_id, attachment_id)? {
+        tracing::debug!("attachment already stored");
+        return Ok(false);
+    }
+    let attachment = client.attachment(message_id, attachment_id).await?;
+    METRICS.store("attachment", || {
+        store.insert_attachment(message_id, attachment_id, &attachment)
+    })?;
+    tracing::debug!("attachment stored succesfully");
+    Ok(true)
+}
+
+fn extract_attachment_ids(message: &FullMessage) -> Vec<&AttachmentId> {
+    let mut attachments = Vec::new();
+    let mut parts = Vec::from([&message.payload]);
+    while let Some(part) = parts.pop() {
+        if let Some(attachment_id) = &part.body.attachment_id {
+            attachments.push(attachment_id);
+        }
+        parts.extend(part.parts.iter());
+    }
+    attachments
+}
+
+/// Compares the remote mailbox with the store, without downloading any
+/// message content. With a `filter`, messages only in the store aren't
+/// counted: Gmail would have to be asked about each of them.
+pub async fn check(
+    client: &GmailClient,
+    store: &Store,
+    filter: Option<String>,
+) -> eyre::Result<()> {
+    let filtered = filter.is_some();
+    let mut remote_ids = HashSet::new();
+    let mut missing = Vec::new();
+    let mut incomplete = Vec::new();
+    let mut messages = client.list_messages(filter);
+    while let Some(message) = messages.next().await.transpose()? {
+        match store.message_state(&message.id)? {
+            None | Some(MessageState::Listed) => missing.push(message.id.clone()),
+            Some(MessageState::Complete) => (),
+            Some(_) => incomplete.push(message.id.clone()),
+        }
+        remote_ids.insert(message.id.to_string());
+    }
+
+    println!("remote messages: {}", remote_ids.len());
+    report_ids("missing from the store", &missing);
+    report_ids("missing attachments or raw body", &incomplete);
+    if filtered {
+        println!("only in the store (deleted remotely): not checked with a filter");
+    } else {
+        let local_only = store
+            .message_ids()?
+            .into_iter()
+            .filter(|id| !remote_ids.contains(id.as_str()))
+            .count();
+        println!("only in the store (deleted remotely): {local_only}");
+    }
+    if !missing.is_empty() || !incomplete.is_empty() {
+        eyre::bail!(
+            "archive is incomplete: {} messages missing, {} incomplete",
+            missing.len(),
+            incomplete.len()
+        );
+    }
+    Ok(())
+}
+
+fn report_ids(what: &str, ids: &[MessageId]) {
+    println!("{what}: {}", ids.len());
+    for id in ids.iter().take(MAX_REPORTED_MISSING) {
+        println!("  {id}");
+    }
+    if ids.len() > MAX_REPORTED_MISSING {
+        println!("  ... and {} more", ids.len() - MAX_REPORTED_MISSING);
+    }
+}
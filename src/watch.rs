@@ -0,0 +1,302 @@
+//! `sync --watch`: an initial sync, then more on a schedule until
+//! interrupted, with a heartbeat file for monitoring.
+
+use crate::{
+    client::GmailClient, config::SyncConfig, oauth::InvalidGrant, progress::ProgressFormat,
+    shutdown::Interrupted, store::Store, sync,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How often the heartbeat is rewritten, including during long syncs.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Minimum delay after a failed sync, doubled with every consecutive
+/// failure up to `MAX_FAILURE_BACKOFF`. Syncs still wait for their schedule
+/// if that's later.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(6 * 3600);
+
+pub enum Schedule {
+    Interval(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    fn next_after(&self, time: DateTime<Utc>) -> eyre::Result<DateTime<Utc>> {
+        match self {
+            Schedule::Interval(interval) => Ok(time + *interval),
+            Schedule::Cron(schedule) => schedule
+                .after(&time)
+                .next()
+                .ok_or_else(|| eyre::eyre!("the cron expression has no upcoming time")),
+        }
+    }
+}
+
+/// Parses a cron expression, in the usual five fields (minute to day of
+/// week) with days of the week numbered as in crontab, or in the six or
+/// seven fields of the `cron` crate, with seconds, years and Sunday as 1.
+pub fn parse_cron(expression: &str) -> eyre::Result<Box<cron::Schedule>> {
+    let fields: Vec<_> = expression.split_whitespace().collect();
+    let expression = match fields[..] {
+        [minute, hour, day, month, weekday] => format!(
+            "0 {minute} {hour} {day} {month} {}",
+            crate_weekdays(weekday)?
+        ),
+        _ => expression.to_owned(),
+    };
+    Ok(Box::new(cron::Schedule::from_str(&expression)?))
+}
+
+/// Converts a crontab day of week field, where Sunday is 0 or 7, to the
+/// `cron` crate's numbering, from 1 for Sunday to 7 for Saturday. Names are
+/// the same in both.
+fn crate_weekdays(field: &str) -> eyre::Result<String> {
+    let number = |value: &str| -> eyre::Result<Option<u32>> {
+        match value.parse::<u32>() {
+            Ok(day) if day <= 7 => Ok(Some(day)),
+            Ok(day) => eyre::bail!("invalid day of week {day}, expected 0 to 7"),
+            Err(_) => Ok(None),
+        }
+    };
+    let convert = |day: u32| day % 7 + 1;
+    let mut converted = Vec::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (item, None),
+        };
+        let range = match range.split_once('-') {
+            None => match number(range)? {
+                Some(day) => convert(day).to_string(),
+                None => range.to_owned(),
+            },
+            Some((start, end)) => match (number(start)?, number(end)?) {
+                (Some(0), Some(7)) => "1-7".to_owned(),
+                // Sunday as 7 wraps around to the start of the week
+                (Some(start), Some(7)) => {
+                    eyre::ensure!(
+                        step.is_none(),
+                        "day of week ranges ending on Sunday can't have a step"
+                    );
+                    format!("{}-7,1", convert(start))
+                }
+                (Some(start), Some(end)) => format!("{}-{}", convert(start), convert(end)),
+                _ => range.to_owned(),
+            },
+        };
+        converted.push(match step {
+            Some(step) => format!("{range}/{step}"),
+            None => range,
+        });
+    }
+    Ok(converted.join(","))
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum WatchState {
+    Syncing,
+    Waiting,
+}
+
+#[derive(Serialize)]
+struct Heartbeat {
+    pid: u32,
+    updated_at: DateTime<Utc>,
+    state: WatchState,
+    last_success: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    next_sync: Option<DateTime<Utc>>,
+}
+
+/// The heartbeat, rewritten on every change of state and at least every
+/// `HEARTBEAT_INTERVAL`.
+struct HeartbeatFile {
+    path: Option<PathBuf>,
+    heartbeat: Mutex<Heartbeat>,
+}
+
+impl HeartbeatFile {
+    fn update(&self, update: impl FnOnce(&mut Heartbeat)) {
+        update(&mut self.heartbeat.lock().unwrap());
+        self.write();
+    }
+
+    fn write(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let contents = {
+            let mut heartbeat = self.heartbeat.lock().unwrap();
+            heartbeat.updated_at = Utc::now();
+            serde_json::to_vec_pretty(&*heartbeat).expect("serializable heartbeat")
+        };
+        // a failure to write it is what monitoring is there to notice, it
+        // shouldn't stop the syncs
+        if let Err(err) = write_atomically(path, &contents) {
+            tracing::warn!("failed to write heartbeat to {}: {err}", path.display());
+        }
+    }
+}
+
+/// Writes through a temporary file, so that readers never see a partial
+/// heartbeat.
+fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Syncs until `cancel` is cancelled, reusing the same client, and so the
+/// same access token, across syncs. Only interruptions and revoked
+/// authorizations end the loop: other failures are logged and retried.
+pub async fn watch(
+    client: &GmailClient,
+    store: &Store,
+    config: &SyncConfig,
+    progress: ProgressFormat,
+    cancel: &CancellationToken,
+    schedule: &Schedule,
+    heartbeat: Option<PathBuf>,
+) -> eyre::Result<()> {
+    let heartbeat = Arc::new(HeartbeatFile {
+        path: heartbeat,
+        heartbeat: Mutex::new(Heartbeat {
+            pid: std::process::id(),
+            updated_at: Utc::now(),
+            state: WatchState::Syncing,
+            last_success: None,
+            consecutive_failures: 0,
+            last_error: None,
+            next_sync: None,
+        }),
+    });
+    let writer = tokio::spawn({
+        let heartbeat = heartbeat.clone();
+        async move {
+            loop {
+                heartbeat.write();
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+            }
+        }
+    });
+    let result = watch_loop(
+        client, store, config, progress, cancel, schedule, &heartbeat,
+    )
+    .await;
+    writer.abort();
+    result
+}
+
+async fn watch_loop(
+    client: &GmailClient,
+    store: &Store,
+    config: &SyncConfig,
+    progress: ProgressFormat,
+    cancel: &CancellationToken,
+    schedule: &Schedule,
+    heartbeat: &HeartbeatFile,
+) -> eyre::Result<()> {
+    let mut failures = 0;
+    loop {
+        heartbeat.update(|heartbeat| {
+            heartbeat.state = WatchState::Syncing;
+            heartbeat.next_sync = None;
+        });
+        let started = Utc::now();
+        match sync::fetch_everything(client, store, config, progress, cancel).await {
+            Ok(()) => {
+                failures = 0;
+                heartbeat.update(|heartbeat| {
+                    heartbeat.last_success = Some(Utc::now());
+                    heartbeat.consecutive_failures = 0;
+                    heartbeat.last_error = None;
+                });
+            }
+            Err(err)
+                if err.downcast_ref::<Interrupted>().is_some()
+                    || err.downcast_ref::<InvalidGrant>().is_some() =>
+            {
+                return Err(err);
+            }
+            Err(err) => {
+                failures += 1;
+                tracing::error!("sync failed ({failures} in a row): {err:#}");
+                heartbeat.update(|heartbeat| {
+                    heartbeat.consecutive_failures = failures;
+                    heartbeat.last_error = Some(format!("{err:#}"));
+                });
+            }
+        }
+
+        let mut next = schedule.next_after(started)?.max(Utc::now());
+        if failures > 0 {
+            let backoff = FAILURE_BACKOFF
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(MAX_FAILURE_BACKOFF);
+            next = next.max(Utc::now() + backoff);
+        }
+        tracing::info!("next sync at {}", next.format("%Y-%m-%d %H:%M:%S UTC"));
+        heartbeat.update(|heartbeat| {
+            heartbeat.state = WatchState::Waiting;
+            heartbeat.next_sync = Some(next);
+        });
+        let delay = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            () = tokio::time::sleep(delay) => (),
+            () = cancel.cancelled() => {
+                tracing::info!("stopped while waiting for the next sync");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    fn weekdays(expression: &str) -> Vec<Weekday> {
+        // a Monday
+        let start = Utc.with_ymd_and_hms(2025, 6, 2, 0, 0, 0).unwrap();
+        parse_cron(expression)
+            .unwrap()
+            .after(&start)
+            .take(7)
+            .map(|time| time.weekday())
+            .collect()
+    }
+
+    #[test]
+    fn numbers_weekdays_as_crontab() {
+        use Weekday::*;
+        assert_eq!(weekdays("0 3 * * 1-5"), [Mon, Tue, Wed, Thu, Fri, Mon, Tue]);
+        assert_eq!(weekdays("0 3 * * 0"), [Sun; 7]);
+        assert_eq!(weekdays("0 3 * * 7"), [Sun; 7]);
+        assert_eq!(weekdays("0 3 * * 5-7"), [Fri, Sat, Sun, Fri, Sat, Sun, Fri]);
+        assert_eq!(
+            weekdays("0 3 * * 1,3/2"),
+            [Mon, Wed, Fri, Mon, Wed, Fri, Mon]
+        );
+        assert_eq!(
+            weekdays("0 3 * * 0-7/2"),
+            [Tue, Thu, Sat, Sun, Tue, Thu, Sat]
+        );
+        assert_eq!(weekdays("0 3 * * SAT"), [Sat; 7]);
+        // the crate's own numbering with six fields
+        assert_eq!(weekdays("0 0 3 * * 1"), [Sun; 7]);
+        assert!(parse_cron("0 3 * * 8").is_err());
+    }
+}